@@ -0,0 +1,126 @@
+// Load generator for a running server: creates many pastes at once, then uploads and downloads
+// each of them concurrently, and reports how long each phase took.
+//
+// cargo run --release --example bench -- [--http2] [http://127.0.0.1:3000] [pastes] [bytes]
+//
+// With --http2 every request goes over a single h2c connection instead, which with pastes larger
+// than the 64KiB initial window also exercises flow control between uploads and downloads.
+//
+// The server's max_content_length must allow the paste size, and the open file limit (ulimit -n)
+// should allow about three connections per paste.
+
+extern crate futures;
+extern crate hyper;
+
+use futures::{future, stream, Future, Stream};
+use hyper::{Body, Client, Method, Request, StatusCode};
+use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+fn main() {
+    let mut args: Vec<String> = env::args().collect();
+    let http2 = args.iter().any(|a| a == "--http2");
+    args.retain(|a| a != "--http2");
+    let base = args
+        .get(1)
+        .cloned()
+        .unwrap_or_else(|| String::from("http://127.0.0.1:3000"));
+    let pastes: usize = args.get(2).map_or(2000, |a| a.parse().expect("pastes"));
+    let size: usize = args.get(3).map_or(1024, |a| a.parse().expect("bytes"));
+
+    let content: Vec<u8> = (0..size).map(|i| b'a' + (i % 26) as u8).collect();
+    let failures = Arc::new(AtomicUsize::new(0));
+
+    let client = Client::builder().http2_only(http2).build_http();
+
+    let counter = failures.clone();
+    let run = future::lazy(move || {
+        let start = Instant::now();
+
+        let request_client = client.clone();
+        let request_base = base.clone();
+        stream::iter_ok(0..pastes)
+            .map(move |_| {
+                let uri = format!("{}/1/id/request?length={}", request_base, size);
+                request_client
+                    .request(Request::post(uri.as_str()).body(Body::empty()).unwrap())
+                    .and_then(|res| res.into_body().concat2())
+                    .map(|body| {
+                        let combo = String::from_utf8(body.to_vec()).unwrap();
+                        let mut parts = combo.split(',');
+                        let id = parts.next().unwrap().to_owned();
+                        let secret = parts.next().unwrap().to_owned();
+                        (id, secret)
+                    })
+            })
+            .buffer_unordered(256)
+            .collect()
+            .and_then(move |ids: Vec<(String, String)>| {
+                let requested = Instant::now();
+                println!(
+                    "requested {} ids in {:?} ({:.0}/s)",
+                    ids.len(),
+                    requested - start,
+                    rate(ids.len(), start, requested)
+                );
+
+                let transfers = ids.into_iter().map(move |(id, secret)| {
+                    let upload = Request::builder()
+                        .method(Method::POST)
+                        .uri(format!(
+                            "{}/1/file/upload?id={}&secret={}",
+                            base, id, secret
+                        ))
+                        .body(Body::from(content.clone()))
+                        .unwrap();
+                    let download = Request::get(format!("{}/1/file/download?id={}", base, id))
+                        .body(Body::empty())
+                        .unwrap();
+
+                    let failures = counter.clone();
+                    let expected = content.len();
+                    client
+                        .request(upload)
+                        .join(client.request(download).and_then(|res| {
+                            let status = res.status();
+                            res.into_body().concat2().map(move |b| (status, b.len()))
+                        }))
+                        .then(move |result| {
+                            match result {
+                                Ok((up, (StatusCode::OK, len)))
+                                    if up.status() == StatusCode::OK && len == expected => {}
+                                _ => {
+                                    failures.fetch_add(1, Ordering::SeqCst);
+                                }
+                            }
+                            Ok(())
+                        })
+                });
+
+                future::join_all(transfers).map(move |done: Vec<()>| {
+                    let finished = Instant::now();
+                    println!(
+                        "transferred {} pastes in {:?} ({:.0}/s)",
+                        done.len(),
+                        finished - requested,
+                        rate(done.len(), requested, finished)
+                    );
+                })
+            })
+            .map_err(|e| eprintln!("bench error: {}", e))
+    });
+
+    hyper::rt::run(run);
+
+    let failed = failures.load(Ordering::SeqCst);
+    if failed > 0 {
+        println!("{} transfers failed", failed);
+    }
+}
+
+fn rate(count: usize, from: Instant, to: Instant) -> f64 {
+    let elapsed = to - from;
+    count as f64 / (elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9)
+}
@@ -0,0 +1,121 @@
+// API v2: pastes are resources at "/2/pastes/{id}", created with POST, uploaded to with PUT,
+// downloaded with GET and retired with DELETE. The secret goes in an "Authorization: Bearer"
+// header rather than the query. Responses other than content are JSON, errors included.
+//
+// The work itself is done by the same code as API v1, which takes the same query arguments.
+
+use futures::future;
+use hyper::header::{self, HeaderValue};
+use hyper::rt::Future;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use url;
+
+use error::{json_string, ApiError, TYPE_JSON};
+use router::{Miss, Params, Router};
+use {
+    bearer_token, create_paste, download_paste, expires_value, fail, normalize_id, public_link,
+    retire_paste, upload_paste, Bod, BoxFut, BoxFutRes, Cluster, InFlightMap, RendezvousPayload,
+    EXPIRES_HEADER,
+};
+
+type Handler = fn(Request<Body>, &Params, &InFlightMap, &Cluster) -> BoxFutRes;
+
+lazy_static! {
+    static ref ROUTER: Router<Handler> = Router::default()
+        .add(Method::POST, "/2/pastes", create as Handler)
+        .add(Method::GET, "/2/pastes/{id}", download as Handler)
+        .add(Method::PUT, "/2/pastes/{id}", upload as Handler)
+        .add(Method::DELETE, "/2/pastes/{id}", retire as Handler);
+}
+
+fn json_response(status: StatusCode, json: String) -> Response<RendezvousPayload> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, TYPE_JSON)
+        .body(Bod(Body::from(json)))
+        .unwrap()
+}
+
+// Errors are rendered as JSON by the caller.
+pub fn service(req: Request<Body>, in_flight: &InFlightMap, cluster: &Cluster) -> BoxFut {
+    let result = match ROUTER.route(req.method(), req.uri().path()) {
+        Ok((handler, params)) => handler(req, &params, in_flight, cluster),
+        Err(Miss::NotFound) => Err(fail(ApiError::NotFound)),
+        Err(Miss::MethodNotAllowed(allowed)) => Err(fail(ApiError::MethodNotAllowed(allowed))),
+    };
+
+    match result {
+        Ok(r) => r,
+        Err(r) => r,
+    }
+}
+
+fn path_id(params: &Params) -> String {
+    normalize_id(params.get("id").unwrap().to_owned())
+}
+
+fn secret(req: &Request<Body>) -> Result<String, BoxFut> {
+    match bearer_token(req.headers()) {
+        Some(secret) => Ok(secret.to_owned()),
+        None => Err(fail(ApiError::SecretRequired)),
+    }
+}
+
+// Answered with the id and secret, and a link to the paste's landing page to share.
+fn create(req: Request<Body>, _: &Params, in_flight: &InFlightMap, cluster: &Cluster) -> BoxFutRes {
+    let (id, secret, expiration) = create_paste(req.uri(), req.headers(), in_flight, cluster)?;
+    let id_path = url::form_urlencoded::byte_serialize(id.as_bytes()).collect::<String>();
+    let link = |path: &str| public_link(req.uri(), req.headers(), path);
+
+    let mut response = json_response(
+        StatusCode::CREATED,
+        format!(
+            r#"{{"id":{},"secret":{},"url":{}}}"#,
+            json_string(&id),
+            json_string(&secret),
+            json_string(&link(&format!("/p/{}", id_path)))
+        ),
+    );
+    let location = link(&format!("/2/pastes/{}", id_path));
+    if let Ok(location) = HeaderValue::from_str(&location) {
+        response.headers_mut().insert(header::LOCATION, location);
+    }
+    response
+        .headers_mut()
+        .insert(EXPIRES_HEADER, expires_value(expiration));
+    Ok(Box::new(future::ok(response)))
+}
+
+// The content itself, as from v1.
+fn download(
+    req: Request<Body>,
+    params: &Params,
+    in_flight: &InFlightMap,
+    _: &Cluster,
+) -> BoxFutRes {
+    download_paste(path_id(params), req.uri(), req.headers(), in_flight)
+}
+
+// Answered once the content has been sent on to a downloader.
+fn upload(req: Request<Body>, params: &Params, in_flight: &InFlightMap, _: &Cluster) -> BoxFutRes {
+    let secret = secret(&req)?;
+    let sent = upload_paste(req, path_id(params), secret, in_flight)?;
+    Ok(Box::new(sent.map(|response| {
+        if response.status().is_success() {
+            json_response(StatusCode::OK, r#"{"status":"sent"}"#.to_owned())
+        } else {
+            response
+        }
+    })))
+}
+
+fn retire(req: Request<Body>, params: &Params, in_flight: &InFlightMap, _: &Cluster) -> BoxFutRes {
+    let secret = secret(&req)?;
+    retire_paste(path_id(params), &secret, in_flight)?;
+    Ok(Box::new(future::ok(
+        Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Bod(Body::empty()))
+            .unwrap(),
+    )))
+}
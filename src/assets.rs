@@ -0,0 +1,204 @@
+// The web pages and scripts the server hands out. Each can be replaced by a file of the same name
+// in assets_dir, e.g. to brand the uploader page, and other files there are served under
+// /static/. With assets_reload, files are checked for changes on every request instead of being
+// read once.
+
+use bytes::Bytes;
+use hyper::header::{self, HeaderValue};
+use hyper::{Body, HeaderMap, Response, StatusCode};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use {Bod, RendezvousPayload};
+
+static EMBEDDED: &[(&str, &[u8])] = &[
+    ("uploader.html", include_bytes!("uploader.html")),
+    ("page.html", include_bytes!("page.html")),
+    ("client.js", include_bytes!("client.js")),
+    ("landing.js", include_bytes!("landing.js")),
+    ("pair.js", include_bytes!("pair.js")),
+    ("words.txt", include_bytes!("words.txt")),
+    ("favicon.ico", include_bytes!("favicon.ico")),
+];
+
+pub fn embedded(name: &str) -> Option<Bytes> {
+    EMBEDDED
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, content)| Bytes::from_static(content))
+}
+
+lazy_static! {
+    static ref BUILT_IN: HashMap<&'static str, Asset> = EMBEDDED
+        .iter()
+        .map(|(name, content)| (*name, Asset::new(Bytes::from_static(content))))
+        .collect();
+}
+
+// Content along with its ETag, so that is only worked out when the content changes.
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub struct Asset {
+    pub content: Bytes,
+    etag: String,
+}
+
+impl Asset {
+    pub fn new(content: Bytes) -> Asset {
+        let hash = Sha256::digest(&content);
+        let hex: String = hash[..8].iter().map(|b| format!("{:02x}", b)).collect();
+        Asset {
+            content,
+            etag: format!("\"{}\"", hex),
+        }
+    }
+}
+
+#[cfg_attr(debug_assertions, derive(Debug))]
+struct Loaded {
+    // None when there was no file, and the embedded copy is used. Only kept for the built-in
+    // names, so asking for files that aren't there doesn't fill the map.
+    asset: Option<Asset>,
+    modified: Option<SystemTime>,
+}
+
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub struct Assets {
+    dir: Option<PathBuf>,
+    reload: bool,
+    loaded: Mutex<HashMap<String, Loaded>>,
+}
+
+impl Assets {
+    pub fn new(dir: Option<PathBuf>, reload: bool) -> Assets {
+        Assets {
+            dir,
+            reload,
+            loaded: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Only plain relative paths, so nothing outside the directory can be asked for.
+    fn path(&self, name: &str) -> Option<PathBuf> {
+        let dir = self.dir.as_ref()?;
+        let relative = Path::new(name);
+        if name.is_empty()
+            || !relative
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return None;
+        }
+        Some(dir.join(relative))
+    }
+
+    // The file of that name in assets_dir. The lock is only held to look it up and store it, not
+    // while the file is checked or read.
+    fn read(&self, name: &str) -> Option<Asset> {
+        let path = self.path(name)?;
+
+        let cached = self
+            .loaded
+            .lock()
+            .unwrap()
+            .get(name)
+            .map(|l| (l.asset.clone(), l.modified));
+        let modified = match cached {
+            Some((asset, _)) if !self.reload => return asset,
+            Some((asset, was)) => {
+                let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+                if modified == was {
+                    return asset;
+                }
+                modified
+            }
+            None => fs::metadata(&path).and_then(|m| m.modified()).ok(),
+        };
+
+        let asset = fs::read(&path).ok().map(|c| Asset::new(Bytes::from(c)));
+        let mut loaded = self.loaded.lock().unwrap();
+        if asset.is_some() || BUILT_IN.contains_key(name) {
+            loaded.insert(
+                name.to_owned(),
+                Loaded {
+                    asset: asset.clone(),
+                    modified,
+                },
+            );
+        } else {
+            loaded.remove(name);
+        }
+        asset
+    }
+
+    // One of the built-in assets, from assets_dir if it has a copy.
+    pub fn get(&self, name: &str) -> Asset {
+        self.read(name)
+            .or_else(|| BUILT_IN.get(name).cloned())
+            .unwrap_or_else(|| Asset::new(Bytes::new()))
+    }
+
+    // Anything else in assets_dir.
+    pub fn get_static(&self, name: &str) -> Option<Asset> {
+        self.read(name)
+    }
+}
+
+pub fn content_type(name: &str) -> &'static str {
+    match Path::new(name).extension().and_then(|e| e.to_str()) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") => "application/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff2") => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+// The content with an ETag, or 304 if the client's copy is still good. max_age of 0 has clients
+// check back every time.
+pub fn response(
+    asset: Asset,
+    content_type: &'static str,
+    max_age: u64,
+    headers: &HeaderMap,
+) -> Response<RendezvousPayload> {
+    let Asset { content, etag } = asset;
+    let cache_control = if max_age == 0 {
+        "no-cache".to_owned()
+    } else {
+        format!("public, max-age={}", max_age)
+    };
+
+    let fresh = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|h| h.split(',').any(|t| t.trim() == etag || t.trim() == "*"));
+
+    let mut response = Response::builder();
+    response
+        .header(header::ETAG, HeaderValue::from_str(&etag).unwrap())
+        .header(header::CACHE_CONTROL, cache_control);
+    if fresh {
+        response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Bod(Body::empty()))
+            .unwrap()
+    } else {
+        response
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Bod(Body::from(content)))
+            .unwrap()
+    }
+}
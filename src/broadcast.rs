@@ -0,0 +1,475 @@
+// Serves one transfer to several downloaders at once. Chunks read from the source are kept until
+// every downloader has read them, and for the join window also for those yet to arrive. Once the
+// fastest downloader is the limit ahead of the slowest it waits for it, unless the slowest has
+// stopped reading altogether for the lag timeout, in which case it is cut off rather than holding
+// up the rest, and its response ends early.
+
+use bytes::Bytes;
+use futures::task::{self, Task};
+use futures::{Async, Future, Poll};
+use futures_timer::Delay;
+use hyper::body::Payload;
+use hyper::{Chunk, HeaderMap};
+use std::collections::{HashMap, VecDeque};
+#[cfg(debug_assertions)]
+use std::fmt;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+#[cfg_attr(debug_assertions, derive(Debug))]
+struct Reader {
+    position: u64,
+    last_read: Instant,
+}
+
+struct Tee<S> {
+    // Polled without the tee locked, by one downloader at a time, while `reading` is set.
+    source: Arc<Mutex<S>>,
+    reading: bool,
+    length: Option<u64>,
+    // Chunks not yet read by every downloader, starting at offset `start` of the content.
+    buffer: VecDeque<Bytes>,
+    start: u64,
+    end: u64,
+    finished: bool,
+    limit: u64,
+    lag_timeout: Duration,
+    // Until then everything is kept from the start, as long as it fits in the limit.
+    joinable_until: Instant,
+    readers: HashMap<usize, Reader>,
+    next_reader: usize,
+    // Downloaders waiting for the source, which only wakes whoever polled it last, or for the
+    // slowest downloader to make room in the buffer.
+    waiting: Vec<Task>,
+    // Wakes a waiting downloader when the slowest one is due to be cut off.
+    lag_timer: Option<Delay>,
+}
+
+#[cfg(debug_assertions)]
+impl<S> fmt::Debug for Tee<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Tee")
+            .field("start", &self.start)
+            .field("end", &self.end)
+            .field("finished", &self.finished)
+            .field("readers", &self.readers)
+            .finish()
+    }
+}
+
+impl<S> Tee<S> {
+    // Only within the join window, and while nothing has been dropped from the buffer, can a
+    // newcomer still get every byte.
+    fn joinable(&self) -> bool {
+        Instant::now() < self.joinable_until && self.start == 0 && !self.readers.is_empty()
+    }
+
+    fn add_reader(&mut self) -> usize {
+        let reader = self.next_reader;
+        self.next_reader += 1;
+        self.readers.insert(
+            reader,
+            Reader {
+                position: self.start,
+                last_read: Instant::now(),
+            },
+        );
+        reader
+    }
+
+    // Have the current task woken along with the rest, once.
+    fn wait(&mut self) {
+        if !self.waiting.iter().any(Task::will_notify_current) {
+            self.waiting.push(task::current());
+        }
+    }
+
+    fn wake_all(&mut self) {
+        for task in self.waiting.drain(..) {
+            task.notify();
+        }
+    }
+
+    // Drop chunks everyone has read, letting anyone waiting on the slowest downloader go on.
+    fn trim(&mut self) {
+        if self.start == 0 && self.end <= self.limit && Instant::now() < self.joinable_until {
+            return;
+        }
+
+        let slowest = self
+            .readers
+            .values()
+            .map(|r| r.position)
+            .min()
+            .unwrap_or(self.end);
+
+        let mut trimmed = false;
+        while let Some(len) = self.buffer.front().map(|c| c.len() as u64) {
+            if self.start + len > slowest {
+                break;
+            }
+            self.buffer.pop_front();
+            self.start += len;
+            trimmed = true;
+        }
+        if trimmed {
+            self.wake_all();
+        }
+    }
+
+    // Whether the caller may read on from the source, or has to wait for the slowest downloader
+    // to catch up. Cuts off the slowest if it has stopped reading.
+    fn poll_room(&mut self) -> Async<()> {
+        loop {
+            let (slowest, last_read) = match self.readers.iter().min_by_key(|(_, r)| r.position) {
+                Some((slowest, reader)) if self.end - reader.position >= self.limit => {
+                    (*slowest, reader.last_read)
+                }
+                _ => break,
+            };
+
+            let deadline = last_read + self.lag_timeout;
+            if Instant::now() >= deadline {
+                self.readers.remove(&slowest);
+                self.trim();
+                continue;
+            }
+
+            self.wait();
+            match self.lag_timer {
+                Some(ref mut timer) => timer.reset_at(deadline),
+                None => self.lag_timer = Some(Delay::new_at(deadline)),
+            }
+            match self.lag_timer.as_mut().unwrap().poll() {
+                Ok(Async::NotReady) => return Async::NotReady,
+                // due now, or the timer broke and it's as good a time as any
+                _ => continue,
+            }
+        }
+        Async::Ready(())
+    }
+}
+
+// Kept by the paste so later downloaders can attach to the transfer.
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub struct Joiner<S> {
+    tee: Weak<Mutex<Tee<S>>>,
+}
+
+impl<S> Joiner<S> {
+    pub fn join(&self) -> Option<Tap<S>> {
+        let tee = self.tee.upgrade()?;
+        let reader = {
+            let mut locked = tee.lock().unwrap();
+            if !locked.joinable() {
+                return None;
+            }
+            locked.add_reader()
+        };
+        Some(Tap { tee, reader })
+    }
+
+    // Whether a downloader arriving now would get the whole transfer.
+    pub fn is_joinable(&self) -> bool {
+        self.tee
+            .upgrade()
+            .is_some_and(|tee| tee.lock().unwrap().joinable())
+    }
+}
+
+// One downloader's view of the transfer.
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub struct Tap<S> {
+    tee: Arc<Mutex<Tee<S>>>,
+    reader: usize,
+}
+
+pub fn start<S: Payload<Data = Chunk>>(
+    source: S,
+    window: Duration,
+    limit: u64,
+    lag_timeout: Duration,
+) -> (Joiner<S>, Tap<S>) {
+    let length = source.content_length();
+    let tee = Arc::new(Mutex::new(Tee {
+        source: Arc::new(Mutex::new(source)),
+        reading: false,
+        length,
+        buffer: VecDeque::new(),
+        start: 0,
+        end: 0,
+        finished: false,
+        limit,
+        lag_timeout,
+        joinable_until: Instant::now() + window,
+        readers: HashMap::new(),
+        next_reader: 0,
+        waiting: Vec::new(),
+        lag_timer: None,
+    }));
+    let reader = tee.lock().unwrap().add_reader();
+
+    (
+        Joiner {
+            tee: Arc::downgrade(&tee),
+        },
+        Tap { tee, reader },
+    )
+}
+
+impl<S: Payload<Data = Chunk>> Payload for Tap<S> {
+    type Data = Chunk;
+    type Error = S::Error;
+
+    fn poll_data(&mut self) -> Poll<Option<Chunk>, S::Error> {
+        let mut tee = self.tee.lock().unwrap();
+        let position = match tee.readers.get(&self.reader) {
+            Some(reader) => reader.position,
+            // cut off for falling behind
+            None => return Ok(Async::Ready(None)),
+        };
+
+        if position < tee.end {
+            let mut offset = tee.start;
+            let mut data = None;
+            for chunk in &tee.buffer {
+                let len = chunk.len() as u64;
+                if position < offset + len {
+                    data = Some(chunk.slice_from((position - offset) as usize));
+                    break;
+                }
+                offset += len;
+            }
+            let data = data.unwrap();
+
+            tee.readers.insert(
+                self.reader,
+                Reader {
+                    position: position + data.len() as u64,
+                    last_read: Instant::now(),
+                },
+            );
+            tee.trim();
+            return Ok(Async::Ready(Some(Chunk::from(data))));
+        }
+
+        if tee.finished {
+            return Ok(Async::Ready(None));
+        }
+        if tee.reading {
+            // someone else is, and wakes everyone when they get something
+            tee.wait();
+            return Ok(Async::NotReady);
+        }
+        if let Async::NotReady = tee.poll_room() {
+            return Ok(Async::NotReady);
+        }
+
+        // the source may be another transfer with locks of its own, so don't hold this one
+        tee.reading = true;
+        let source = tee.source.clone();
+        drop(tee);
+        let polled = source.lock().unwrap().poll_data();
+        let mut tee = self.tee.lock().unwrap();
+        tee.reading = false;
+
+        match polled {
+            Ok(Async::Ready(Some(chunk))) => {
+                let data = chunk.into_bytes();
+                tee.end += data.len() as u64;
+                tee.buffer.push_back(data.clone());
+                let end = tee.end;
+                let cut_off = match tee.readers.get_mut(&self.reader) {
+                    Some(reader) => {
+                        reader.position = end;
+                        reader.last_read = Instant::now();
+                        false
+                    }
+                    // while the source was being read
+                    None => true,
+                };
+                tee.trim();
+                tee.wake_all();
+                if cut_off {
+                    return Ok(Async::Ready(None));
+                }
+                Ok(Async::Ready(Some(Chunk::from(data))))
+            }
+            Ok(Async::Ready(None)) => {
+                tee.finished = true;
+                tee.wake_all();
+                Ok(Async::Ready(None))
+            }
+            Ok(Async::NotReady) => {
+                tee.wait();
+                Ok(Async::NotReady)
+            }
+            Err(e) => {
+                // everyone else just sees their response end early
+                tee.finished = true;
+                tee.wake_all();
+                Err(e)
+            }
+        }
+    }
+
+    fn poll_trailers(&mut self) -> Poll<Option<HeaderMap>, S::Error> {
+        Ok(Async::Ready(None))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        let tee = self.tee.lock().unwrap();
+        tee.finished
+            && tee
+                .readers
+                .get(&self.reader)
+                .is_none_or(|r| r.position == tee.end)
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        self.tee.lock().unwrap().length
+    }
+}
+
+impl<S> Drop for Tap<S> {
+    fn drop(&mut self) {
+        let mut tee = self.tee.lock().unwrap();
+        tee.readers.remove(&self.reader);
+        tee.trim();
+        // this may have been the one the source was going to wake
+        tee.wake_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::{self, Notify, NotifyHandle};
+    use futures::future;
+    use std::io;
+
+    // Hands out its chunks as soon as asked.
+    struct Chunks(VecDeque<&'static str>);
+
+    impl Payload for Chunks {
+        type Data = Chunk;
+        type Error = io::Error;
+
+        fn poll_data(&mut self) -> Poll<Option<Chunk>, io::Error> {
+            Ok(Async::Ready(self.0.pop_front().map(Chunk::from)))
+        }
+    }
+
+    struct Ignore;
+
+    impl Notify for Ignore {
+        fn notify(&self, _: usize) {}
+    }
+
+    fn tee(
+        chunks: &[&'static str],
+        window_secs: u64,
+        limit: u64,
+        lag_timeout_secs: u64,
+    ) -> (Joiner<Chunks>, Tap<Chunks>) {
+        start(
+            Chunks(chunks.iter().cloned().collect()),
+            Duration::from_secs(window_secs),
+            limit,
+            Duration::from_secs(lag_timeout_secs),
+        )
+    }
+
+    // The next chunk, if it is there to read without waiting.
+    fn read(tap: &mut Tap<Chunks>) -> Async<Option<String>> {
+        let notify = NotifyHandle::from(Arc::new(Ignore));
+        let mut polled = executor::spawn(future::poll_fn(|| tap.poll_data().map(Async::Ready)));
+        match polled.poll_future_notify(&notify, 0).unwrap() {
+            Async::Ready(Async::Ready(chunk)) => {
+                Async::Ready(chunk.map(|c| String::from_utf8(c.to_vec()).unwrap()))
+            }
+            _ => Async::NotReady,
+        }
+    }
+
+    fn read_all(tap: &mut Tap<Chunks>) -> String {
+        let mut all = String::new();
+        while let Async::Ready(Some(chunk)) = read(tap) {
+            all.push_str(&chunk);
+        }
+        all
+    }
+
+    #[test]
+    fn joiners_in_the_window_get_everything() {
+        let (joiner, mut first) = tee(&["ab", "cd"], 60, 1024, 60);
+        assert_eq!(read(&mut first), Async::Ready(Some("ab".to_owned())));
+
+        let mut second = joiner.join().unwrap();
+        assert_eq!(read_all(&mut first), "cd");
+        assert_eq!(read_all(&mut second), "abcd");
+    }
+
+    #[test]
+    fn nobody_joins_after_the_window() {
+        let (joiner, mut first) = tee(&["ab"], 0, 1024, 60);
+        assert!(!joiner.is_joinable());
+        assert!(joiner.join().is_none());
+        assert_eq!(read_all(&mut first), "ab");
+    }
+
+    #[test]
+    fn nobody_joins_once_the_start_is_dropped() {
+        let (joiner, mut first) = tee(&["ab", "cd", "ef"], 60, 2, 60);
+        assert_eq!(read(&mut first), Async::Ready(Some("ab".to_owned())));
+        assert!(joiner.is_joinable());
+
+        // past the limit, what everyone has read goes
+        assert_eq!(read(&mut first), Async::Ready(Some("cd".to_owned())));
+        assert_eq!(first.tee.lock().unwrap().start, 4);
+        assert!(joiner.join().is_none());
+    }
+
+    #[test]
+    fn read_chunks_are_trimmed() {
+        let (joiner, mut first) = tee(&["ab", "cd"], 60, 1, 60);
+        let mut second = joiner.join().unwrap();
+
+        assert_eq!(read(&mut first), Async::Ready(Some("ab".to_owned())));
+        assert_eq!(first.tee.lock().unwrap().buffer.len(), 1);
+        assert_eq!(read(&mut second), Async::Ready(Some("ab".to_owned())));
+        assert_eq!(first.tee.lock().unwrap().buffer.len(), 0);
+    }
+
+    #[test]
+    fn the_fastest_waits_for_the_slowest() {
+        let (joiner, mut fast) = tee(&["ab", "cd"], 60, 2, 60);
+        let mut slow = joiner.join().unwrap();
+
+        assert_eq!(read(&mut fast), Async::Ready(Some("ab".to_owned())));
+        assert_eq!(read(&mut fast), Async::NotReady);
+        assert_eq!(read(&mut slow), Async::Ready(Some("ab".to_owned())));
+        assert_eq!(read_all(&mut fast), "cd");
+    }
+
+    #[test]
+    fn a_stalled_downloader_is_cut_off() {
+        let (joiner, mut fast) = tee(&["ab", "cd"], 60, 2, 0);
+        let mut slow = joiner.join().unwrap();
+
+        assert_eq!(read_all(&mut fast), "abcd");
+        assert_eq!(read(&mut slow), Async::Ready(None));
+        assert!(slow.is_end_stream());
+    }
+
+    #[test]
+    fn leaving_lets_the_rest_go_on() {
+        let (joiner, mut fast) = tee(&["ab", "cd"], 60, 2, 60);
+        let slow = joiner.join().unwrap();
+
+        assert_eq!(read(&mut fast), Async::Ready(Some("ab".to_owned())));
+        assert_eq!(read(&mut fast), Async::NotReady);
+        drop(slow);
+        assert_eq!(read_all(&mut fast), "cd");
+    }
+}
@@ -0,0 +1,573 @@
+// Pastes made of several named files, each uploaded on its own. Downloaders can fetch them one at
+// a time, or all together as a tar or zip archive assembled on the fly from one upload of each
+// file after the other. The archives only store the files, so their length is known up front.
+
+use crc32fast::Hasher;
+use futures::{Async, Future, Poll};
+use futures_timer::Delay;
+use hyper::body::Payload;
+use hyper::{Chunk, HeaderMap};
+use std::error::Error;
+#[cfg(debug_assertions)]
+use std::fmt;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Longer names would need the ustar prefix field or a pax header.
+const MAX_NAME_LENGTH: usize = 100;
+pub const MAX_FILES: usize = 1000;
+
+const TAR_BLOCK: u64 = 512;
+const ZIP_LOCAL_HEADER: u64 = 30;
+const ZIP_DATA_DESCRIPTOR: u64 = 16;
+const ZIP_CENTRAL_HEADER: u64 = 46;
+const ZIP_END_OF_CENTRAL_DIRECTORY: u64 = 22;
+// sizes follow the data, names are UTF-8
+const ZIP_FLAGS: u16 = 0x0808;
+
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub struct Entry {
+    pub name: String,
+    pub length: u64,
+}
+
+// An entry of the manifest given when requesting the id, as "name:length".
+pub fn parse_entry(declared: &str) -> Result<Entry, &'static str> {
+    let colon = declared
+        .rfind(':')
+        .ok_or("Files should be given as name:length")?;
+    let name = &declared[..colon];
+    let length = declared[colon + 1..]
+        .parse::<u64>()
+        .map_err(|_| "File lengths should be decimal integers")?;
+
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+        return Err("File names should be 1 to 100 bytes long");
+    }
+    if name.starts_with('/')
+        || name.ends_with('/')
+        || name.contains('\\')
+        || name.chars().any(char::is_control)
+        || name.split('/').any(|part| part.is_empty() || part == "..")
+    {
+        return Err("File names should be relative paths without \"..\"");
+    }
+
+    Ok(Entry {
+        name: name.to_owned(),
+        length,
+    })
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub enum Format {
+    Tar,
+    Zip,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Format> {
+        match name {
+            "tar" => Some(Format::Tar),
+            "zip" => Some(Format::Zip),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Tar => "application/x-tar",
+            Format::Zip => "application/zip",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Tar => "tar",
+            Format::Zip => "zip",
+        }
+    }
+}
+
+// Padding a tar entry's content out to whole blocks.
+fn tar_padding(length: u64) -> u64 {
+    (TAR_BLOCK - length % TAR_BLOCK) % TAR_BLOCK
+}
+
+pub fn archive_length(format: Format, entries: &[Entry]) -> u64 {
+    match format {
+        // a header block per file, and two empty blocks at the end
+        Format::Tar => {
+            entries
+                .iter()
+                .map(|e| TAR_BLOCK + e.length + tar_padding(e.length))
+                .sum::<u64>()
+                + 2 * TAR_BLOCK
+        }
+        Format::Zip => {
+            entries
+                .iter()
+                .map(|e| {
+                    let name = e.name.len() as u64;
+                    ZIP_LOCAL_HEADER
+                        + name
+                        + e.length
+                        + ZIP_DATA_DESCRIPTOR
+                        + ZIP_CENTRAL_HEADER
+                        + name
+                })
+                .sum::<u64>()
+                + ZIP_END_OF_CENTRAL_DIRECTORY
+        }
+    }
+}
+
+// Without zip64 sizes and offsets have to fit in 32 bits.
+pub fn fits(format: Format, entries: &[Entry]) -> bool {
+    format == Format::Tar
+        || (entries.len() < 0xffff && archive_length(format, entries) <= u64::from(u32::MAX))
+}
+
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+}
+
+fn tar_header(entry: &Entry, mtime: u64) -> Vec<u8> {
+    let mut header = vec![0; TAR_BLOCK as usize];
+    header[..entry.name.len()].copy_from_slice(entry.name.as_bytes());
+    octal(&mut header[100..108], 0o644);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], entry.length);
+    octal(&mut header[136..148], mtime);
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // summed with the checksum field itself taken as spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u64 = header.iter().map(|&b| u64::from(b)).sum();
+    octal(&mut header[148..155], checksum);
+    header[154] = 0;
+    header
+}
+
+// MS-DOS date and time, as zip keeps them.
+fn dos_timestamp(unix: u64) -> (u16, u16) {
+    let days = (unix / 86400) as i64;
+    let seconds = unix % 86400;
+
+    // civil date from days since 1970-01-01
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let date = (((year - 1980).clamp(0, 127) as u16) << 9) | ((month as u16) << 5) | day as u16;
+    let time = (((seconds / 3600) as u16) << 11)
+        | ((((seconds % 3600) / 60) as u16) << 5)
+        | ((seconds % 60) / 2) as u16;
+    (date, time)
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&(value as u32).to_le_bytes());
+}
+
+// A file's CRC is only known once it has gone by, so it comes after the content, in the data
+// descriptor. With flag bit 3 set the sizes go there too, and here the CRC and sizes are all zero.
+fn zip_local_header(entry: &Entry, date: u16, time: u16) -> Vec<u8> {
+    let mut header = Vec::with_capacity((ZIP_LOCAL_HEADER as usize) + entry.name.len());
+    put_u32(&mut header, 0x0403_4b50);
+    put_u16(&mut header, 20);
+    put_u16(&mut header, ZIP_FLAGS);
+    put_u16(&mut header, 0);
+    put_u16(&mut header, time);
+    put_u16(&mut header, date);
+    put_u32(&mut header, 0);
+    put_u32(&mut header, 0);
+    put_u32(&mut header, 0);
+    put_u16(&mut header, entry.name.len() as u16);
+    put_u16(&mut header, 0);
+    header.extend_from_slice(entry.name.as_bytes());
+    header
+}
+
+fn zip_data_descriptor(entry: &Entry, crc: u32) -> Vec<u8> {
+    let mut descriptor = Vec::with_capacity(ZIP_DATA_DESCRIPTOR as usize);
+    put_u32(&mut descriptor, 0x0807_4b50);
+    put_u32(&mut descriptor, u64::from(crc));
+    put_u32(&mut descriptor, entry.length);
+    put_u32(&mut descriptor, entry.length);
+    descriptor
+}
+
+fn zip_central_directory(
+    entries: &[Entry],
+    crcs: &[u32],
+    offsets: &[u64],
+    start: u64,
+    date: u16,
+    time: u16,
+) -> Vec<u8> {
+    let mut directory = Vec::new();
+    for ((entry, &crc), &offset) in entries.iter().zip(crcs).zip(offsets) {
+        put_u32(&mut directory, 0x0201_4b50);
+        put_u16(&mut directory, 20);
+        put_u16(&mut directory, 20);
+        put_u16(&mut directory, ZIP_FLAGS);
+        put_u16(&mut directory, 0);
+        put_u16(&mut directory, time);
+        put_u16(&mut directory, date);
+        put_u32(&mut directory, u64::from(crc));
+        put_u32(&mut directory, entry.length);
+        put_u32(&mut directory, entry.length);
+        put_u16(&mut directory, entry.name.len() as u16);
+        put_u16(&mut directory, 0);
+        put_u16(&mut directory, 0);
+        put_u16(&mut directory, 0);
+        put_u16(&mut directory, 0);
+        put_u32(&mut directory, 0);
+        put_u32(&mut directory, offset);
+        directory.extend_from_slice(entry.name.as_bytes());
+    }
+
+    let size = directory.len() as u64;
+    put_u32(&mut directory, 0x0605_4b50);
+    put_u16(&mut directory, 0);
+    put_u16(&mut directory, 0);
+    put_u16(&mut directory, entries.len() as u16);
+    put_u16(&mut directory, entries.len() as u16);
+    put_u32(&mut directory, size);
+    put_u32(&mut directory, start);
+    put_u16(&mut directory, 0);
+    directory
+}
+
+fn failed(message: String) -> Box<dyn Error + Send + Sync> {
+    Box::new(io::Error::other(message))
+}
+
+// Asks for an upload of the file with the given index, None if there is none yet.
+pub type Fetch<P> = Box<dyn FnMut(usize) -> Option<P> + Send>;
+
+// The archive of a bundle, asking for each file's upload only once it gets to it, so uploads
+// further on aren't held up or taken for stalled meanwhile. If one doesn't turn up after retrying
+// for a while the response ends early.
+pub struct Archive<P> {
+    format: Format,
+    entries: Vec<Entry>,
+    fetch: Fetch<P>,
+    retry_interval: Duration,
+    max_retries: u64,
+    mtime: u64,
+
+    index: usize,
+    current: Option<P>,
+    received: u64,
+    crc: Hasher,
+    retries: u64,
+    retry: Option<Delay>,
+    // CRCs and header offsets of the files so far, for the zip central directory
+    crcs: Vec<u32>,
+    offsets: Vec<u64>,
+    written: u64,
+    finished: bool,
+}
+
+#[cfg(debug_assertions)]
+impl<P> fmt::Debug for Archive<P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Archive")
+            .field("format", &self.format)
+            .field("index", &self.index)
+            .field("written", &self.written)
+            .finish()
+    }
+}
+
+impl<P> Archive<P> {
+    pub fn new(
+        format: Format,
+        entries: Vec<Entry>,
+        fetch: Fetch<P>,
+        retry_interval: Duration,
+        max_retries: u64,
+    ) -> Archive<P> {
+        let mtime = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Archive {
+            format,
+            entries,
+            fetch,
+            retry_interval,
+            max_retries,
+            mtime,
+            index: 0,
+            current: None,
+            received: 0,
+            crc: Hasher::new(),
+            retries: 0,
+            retry: None,
+            crcs: Vec::new(),
+            offsets: Vec::new(),
+            written: 0,
+            finished: false,
+        }
+    }
+
+    fn emit(&mut self, data: Vec<u8>) -> Poll<Option<Chunk>, Box<dyn Error + Send + Sync>> {
+        self.written += data.len() as u64;
+        Ok(Async::Ready(Some(Chunk::from(data))))
+    }
+
+    fn header(&self, entry: &Entry) -> Vec<u8> {
+        match self.format {
+            Format::Tar => tar_header(entry, self.mtime),
+            Format::Zip => {
+                let (date, time) = dos_timestamp(self.mtime);
+                zip_local_header(entry, date, time)
+            }
+        }
+    }
+
+    // What goes between one file's content and the next file's header.
+    fn file_trailer(&self, entry: &Entry, crc: u32) -> Vec<u8> {
+        match self.format {
+            Format::Tar => vec![0; tar_padding(entry.length) as usize],
+            Format::Zip => zip_data_descriptor(entry, crc),
+        }
+    }
+
+    fn archive_trailer(&self) -> Vec<u8> {
+        match self.format {
+            Format::Tar => vec![0; 2 * TAR_BLOCK as usize],
+            Format::Zip => {
+                let (date, time) = dos_timestamp(self.mtime);
+                zip_central_directory(
+                    &self.entries,
+                    &self.crcs,
+                    &self.offsets,
+                    self.written,
+                    date,
+                    time,
+                )
+            }
+        }
+    }
+}
+
+impl<P> Payload for Archive<P>
+where
+    P: Payload<Data = Chunk>,
+    P::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    type Data = Chunk;
+    type Error = Box<dyn Error + Send + Sync>;
+
+    fn poll_data(&mut self) -> Poll<Option<Chunk>, Self::Error> {
+        loop {
+            if self.finished {
+                return Ok(Async::Ready(None));
+            }
+
+            if self.current.is_none() {
+                if self.index == self.entries.len() {
+                    self.finished = true;
+                    let trailer = self.archive_trailer();
+                    return self.emit(trailer);
+                }
+
+                // once due, or if the timer broke, it's as good a time as any to try again
+                if let Some(ref mut retry) = self.retry {
+                    if let Ok(Async::NotReady) = retry.poll() {
+                        return Ok(Async::NotReady);
+                    }
+                }
+                self.retry = None;
+
+                match (self.fetch)(self.index) {
+                    Some(payload) => {
+                        self.current = Some(payload);
+                        self.received = 0;
+                        self.crc = Hasher::new();
+                        self.retries = 0;
+                        self.offsets.push(self.written);
+                        let header = self.header(&self.entries[self.index]);
+                        return self.emit(header);
+                    }
+                    None if self.retries < self.max_retries => {
+                        self.retries += 1;
+                        self.retry = Some(Delay::new(self.retry_interval));
+                        continue;
+                    }
+                    None => {
+                        self.finished = true;
+                        return Err(failed(format!(
+                            "No upload of {} available",
+                            self.entries[self.index].name
+                        )));
+                    }
+                }
+            }
+
+            let length = self.entries[self.index].length;
+            let polled = self
+                .current
+                .as_mut()
+                .unwrap()
+                .poll_data()
+                .map_err(Into::into);
+            match polled {
+                Ok(Async::Ready(Some(chunk))) => {
+                    self.received += chunk.len() as u64;
+                    if self.received > length {
+                        self.finished = true;
+                        return Err(failed(format!(
+                            "Upload of {} is longer than declared",
+                            self.entries[self.index].name
+                        )));
+                    }
+                    self.crc.update(&chunk);
+                    self.written += chunk.len() as u64;
+                    return Ok(Async::Ready(Some(chunk)));
+                }
+                Ok(Async::Ready(None)) => {
+                    if self.received < length {
+                        self.finished = true;
+                        return Err(failed(format!(
+                            "Upload of {} ended early",
+                            self.entries[self.index].name
+                        )));
+                    }
+                    self.current = None;
+                    let crc = self.crc.clone().finalize();
+                    self.crcs.push(crc);
+                    let trailer = self.file_trailer(&self.entries[self.index], crc);
+                    self.index += 1;
+                    if !trailer.is_empty() {
+                        return self.emit(trailer);
+                    }
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => {
+                    self.finished = true;
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    fn poll_trailers(&mut self) -> Poll<Option<HeaderMap>, Self::Error> {
+        Ok(Async::Ready(None))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.finished
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        Some(archive_length(self.format, &self.entries))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Body;
+
+    fn entries(files: &[(&str, u64)]) -> Vec<Entry> {
+        files
+            .iter()
+            .map(|(name, length)| Entry {
+                name: name.to_string(),
+                length: *length,
+            })
+            .collect()
+    }
+
+    // The whole archive, with each file's content made up to its declared length.
+    fn emitted(format: Format, entries: Vec<Entry>) -> Vec<u8> {
+        let lengths: Vec<u64> = entries.iter().map(|e| e.length).collect();
+        let fetch: Fetch<Body> =
+            Box::new(move |i| Some(Body::from(vec![b'x'; lengths[i] as usize])));
+        let mut archive = Archive::new(format, entries, fetch, Duration::from_millis(1), 0);
+
+        let mut bytes = Vec::new();
+        while let Async::Ready(Some(chunk)) = archive.poll_data().unwrap() {
+            bytes.extend_from_slice(&chunk);
+        }
+        bytes
+    }
+
+    #[test]
+    fn archive_length_matches_what_is_sent() {
+        let manifests = [
+            vec![],
+            vec![("a.txt", 5)],
+            vec![("empty", 0), ("dir/b.txt", 512), ("c", 513)],
+            vec![("a-rather-longer-name/with/some/depth.rs", 1000), ("d", 1)],
+        ];
+        for format in &[Format::Tar, Format::Zip] {
+            for manifest in &manifests {
+                let entries = entries(manifest);
+                let expected = archive_length(*format, &entries);
+                assert_eq!(
+                    emitted(*format, entries).len() as u64,
+                    expected,
+                    "{} of {:?}",
+                    format.extension(),
+                    manifest
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn tar_pads_to_whole_blocks() {
+        let tar = emitted(Format::Tar, entries(&[("a.txt", 5)]));
+        assert_eq!(&tar[..5], b"a.txt");
+        assert_eq!(&tar[512..517], b"xxxxx");
+        assert!(tar[517..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn zip_ends_with_its_central_directory() {
+        let zip = emitted(Format::Zip, entries(&[("a.txt", 5), ("b.txt", 0)]));
+        assert_eq!(&zip[..4], b"PK\x03\x04");
+        let end = zip.len() - ZIP_END_OF_CENTRAL_DIRECTORY as usize;
+        assert_eq!(&zip[end..end + 4], b"PK\x05\x06");
+        // two entries
+        assert_eq!(&zip[end + 10..end + 12], &[2, 0]);
+    }
+
+    #[test]
+    fn unsafe_names_are_refused() {
+        assert!(parse_entry("dir/a.txt:5").is_ok());
+        for declared in &[
+            "a.txt",
+            "a.txt:x",
+            ":5",
+            "/etc/passwd:5",
+            "../a:5",
+            "a//b:5",
+            "a\\b:5",
+        ] {
+            assert!(parse_entry(declared).is_err(), "{}", declared);
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use bytes::Bytes;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+// Content of pastes that opted in to being kept on the server, evicting the least recently
+// downloaded once over capacity.
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub struct Cache {
+    capacity: u64,
+    inner: Mutex<Entries>,
+}
+
+#[derive(Default)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+struct Entries {
+    content: HashMap<String, (Bytes, u64)>,
+    // Ids by when they were last used, oldest first.
+    recent: BTreeMap<u64, String>,
+    tick: u64,
+    size: u64,
+}
+
+impl Cache {
+    pub fn new(capacity: u64) -> Cache {
+        Cache {
+            capacity,
+            inner: Mutex::new(Entries::default()),
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<Bytes> {
+        let mut entries = self.inner.lock().unwrap();
+        entries.tick += 1;
+        let tick = entries.tick;
+
+        let (content, last_used) = entries.content.get_mut(id)?;
+        let content = content.clone();
+        let previous = *last_used;
+        *last_used = tick;
+
+        entries.recent.remove(&previous);
+        entries.recent.insert(tick, id.to_owned());
+        Some(content)
+    }
+
+    pub fn insert(&self, id: String, content: Bytes) {
+        let length = content.len() as u64;
+        if length > self.capacity {
+            return;
+        }
+
+        let mut entries = self.inner.lock().unwrap();
+        entries.remove(&id);
+
+        while entries.size + length > self.capacity {
+            let oldest = match entries.recent.keys().next() {
+                Some(tick) => *tick,
+                None => break,
+            };
+            let evicted = entries.recent.remove(&oldest).unwrap();
+            entries.remove(&evicted);
+        }
+
+        entries.tick += 1;
+        let tick = entries.tick;
+        entries.size += length;
+        entries.recent.insert(tick, id.clone());
+        entries.content.insert(id, (content, tick));
+    }
+
+    pub fn remove(&self, id: &str) {
+        self.inner.lock().unwrap().remove(id);
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.inner.lock().unwrap().content.contains_key(id)
+    }
+
+    pub fn size(&self) -> u64 {
+        self.inner.lock().unwrap().size
+    }
+}
+
+impl Entries {
+    fn remove(&mut self, id: &str) {
+        if let Some((content, last_used)) = self.content.remove(id) {
+            self.recent.remove(&last_used);
+            self.size -= content.len() as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache_of(capacity: u64, ids: &[&str]) -> Cache {
+        let cache = Cache::new(capacity);
+        for id in ids {
+            cache.insert(id.to_string(), Bytes::from(vec![0; 4]));
+        }
+        cache
+    }
+
+    #[test]
+    fn the_least_recently_used_is_evicted() {
+        let cache = cache_of(12, &["a", "b", "c"]);
+        assert!(cache.get("a").is_some());
+
+        cache.insert("d".to_owned(), Bytes::from(vec![0; 4]));
+        assert!(!cache.contains("b"));
+        assert!(cache.contains("a") && cache.contains("c") && cache.contains("d"));
+        assert_eq!(cache.size(), 12);
+    }
+
+    #[test]
+    fn as_many_are_evicted_as_it_takes() {
+        let cache = cache_of(12, &["a", "b", "c"]);
+        cache.insert("big".to_owned(), Bytes::from(vec![0; 10]));
+        assert!(!cache.contains("a") && !cache.contains("b") && !cache.contains("c"));
+        assert_eq!(cache.size(), 10);
+    }
+
+    #[test]
+    fn too_big_is_not_kept_and_evicts_nothing() {
+        let cache = cache_of(12, &["a", "b"]);
+        cache.insert("huge".to_owned(), Bytes::from(vec![0; 13]));
+        assert!(!cache.contains("huge"));
+        assert!(cache.contains("a") && cache.contains("b"));
+    }
+
+    #[test]
+    fn replacing_counts_the_new_size_only() {
+        let cache = cache_of(12, &["a", "b"]);
+        cache.insert("a".to_owned(), Bytes::from(vec![1; 2]));
+        assert_eq!(cache.size(), 6);
+        assert_eq!(cache.get("a"), Some(Bytes::from(vec![1; 2])));
+
+        cache.remove("a");
+        assert!(cache.get("a").is_none());
+        assert_eq!(cache.size(), 4);
+    }
+}
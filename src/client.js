@@ -7,6 +7,18 @@ var log = document.getElementById('log');
 var statusdiv = document.getElementById('status-div');
 var link = document.getElementById('link');
 var uploadmeter = document.getElementById('uploadmeter');
+var cachebox = document.getElementById('cache');
+var pairbutton = document.getElementById('pair-button');
+var paircode = document.getElementById('pair-code');
+
+// The pairing code on offer for the current paste, from pair.js.
+var pairing = null;
+
+// Where the server is mounted, and where it is reached from outside if that's different, as
+// filled in by the server.
+var basePath = document.body.getAttribute('data-base-path') || '';
+var publicUrl = document.body.getAttribute('data-public-url') ||
+                window.location.protocol + '//' + window.location.host + basePath;
 
 var reportStatus = function (newStatus) {
   log.value += newStatus + '\n';
@@ -48,11 +60,12 @@ function utf8ByteLength(str) {
   return s;
 }
 
-function requestId(value, loadedCallback, errorCallback) {
+function requestId(value, cache, loadedCallback, errorCallback) {
   reportStatus('Requesting upload id');
 
   var xhr = new XMLHttpRequest();
-  xhr.open('POST', '/1/id/request?length=' + utf8ByteLength(value), true);
+  xhr.open('POST', basePath + '/1/id/request?length=' + utf8ByteLength(value) +
+                   (cache ? '&cache=true' : ''), true);
   addEventHandlers(xhr, loadedCallback, errorCallback);
   xhr.send();
 
@@ -63,7 +76,7 @@ function cancelId(id, secret, loadedCallback, errorCallback) {
   reportStatus('Cancelling');
 
   var xhr = new XMLHttpRequest();
-  xhr.open('POST', '/1/id/retire?id=' + id + '&secret=' + secret);
+  xhr.open('POST', basePath + '/1/id/retire?id=' + id + '&secret=' + secret);
   xhr.send();
   addEventHandlers(xhr, loadedCallback, errorCallback);
 
@@ -74,7 +87,7 @@ function upload(value, id, secret, loadedCallback, errorCallback) {
   reportStatus('Starting upload');
 
   var xhr = new XMLHttpRequest();
-  xhr.open('POST', '/1/file/upload?id=' + id + '&secret=' + secret);
+  xhr.open('POST', basePath + '/1/file/upload?id=' + id + '&secret=' + secret);
   xhr.setRequestHeader("Content-Type", "text/plain; charset=utf-8");
   addEventHandlers(xhr, loadedCallback, errorCallback);
   xhr.send(value);
@@ -82,8 +95,38 @@ function upload(value, id, secret, loadedCallback, errorCallback) {
   return xhr;
 }
 
+// Ask for the content over a WebSocket only when a downloader turns up, instead of keeping an
+// upload of the whole paste pending. Each binary message starts with the transfer number it is
+// part of, so several downloads can be served at once.
+function sendTransfer(socket, bytes, request) {
+  var chunkSize = 65536;
+  var end = request.offset + request.length;
+  for (var offset = request.offset; offset < end; offset += chunkSize) {
+    var chunk = bytes.subarray(offset, Math.min(offset + chunkSize, end));
+    var message = new Uint8Array(4 + chunk.length);
+    new DataView(message.buffer).setUint32(0, request.transfer);
+    message.set(chunk, 4);
+    socket.send(message);
+  }
+}
+
+function closeSocket(socket) {
+  socket.onclose = null;
+  socket.close();
+}
+
+function stopPairing() {
+  if (pairing) {
+    pairing.cancel();
+    pairing = null;
+  }
+  paircode.innerText = '';
+  pairbutton.disabled = false;
+}
+
 function reset() {
   statusdiv.hidden = true;
+  stopPairing();
 
   submit.disabled = false;
   submit.innerText = 'Upload';
@@ -102,7 +145,11 @@ submit.onclick = function onclickSubmit () {
   var uploads = 0;
   var errors = 0;
   var value = content.value;
+  var cache = cachebox.checked;
   var curxhr = null;
+  var cursocket = null;
+  var curevents = null;
+  var retryTimer = null;
   link.value = '';
   uploadmeter.innerText = '0';
 
@@ -113,8 +160,17 @@ submit.onclick = function onclickSubmit () {
   submit.innerText = 'Uploading...';
   submitcancel.hidden = false;
   submitcancel.onclick = function () {
-    curxhr.abort();
-    curxhr = null;
+    if (curxhr) {
+      curxhr.abort();
+      curxhr = null;
+    }
+    if (cursocket) {
+      closeSocket(cursocket);
+      cursocket = null;
+    }
+    clearTimeout(retryTimer);
+    retryTimer = null;
+    stopEvents();
 
     cancelId(id, secret, function () {
       reportStatus('Id retired.');
@@ -129,35 +185,171 @@ submit.onclick = function onclickSubmit () {
   };
 
   function cancelWhenUnloaded () {
-    if (curxhr) {
+    if (curxhr || cursocket || retryTimer) {
       // Here we attempt to automatically retire the transfer when navigating away from the page.
       // We don't depend on this, but it will allow the server to pick up the change before its
       // periodic timeout.
-      curxhr.abort();
-      curxhr = null;
+      if (curxhr) {
+        curxhr.abort();
+        curxhr = null;
+      }
+      if (cursocket) {
+        closeSocket(cursocket);
+        cursocket = null;
+      }
+      clearTimeout(retryTimer);
+      retryTimer = null;
 
       if (navigator && navigator.sendBeacon) {
-        navigator.sendBeacon('/1/id/retire?id=' + id + '&secret=' + secret);
+        navigator.sendBeacon(basePath + '/1/id/retire?id=' + id + '&secret=' + secret);
       } else {
         var xhr = new XMLHttpRequest();
-        xhr.open('POST', '/1/id/retire?id=' + id + '&secret=' + secret, false);
+        xhr.open('POST', basePath + '/1/id/retire?id=' + id + '&secret=' + secret, false);
         xhr.send();
       }
     }
   };
 
-  function uploadSuccess (xhr) {
-    reportStatus('Upload ok: "' + xhr.responseText + '"')
+  function countUpload () {
     uploads += 1;
     uploadmeter.innerText = '' + uploads;
+
+    if (cache && uploads === 1) {
+      // the server has a copy now, though only in memory and only until the cache needs the room
+      window.removeEventListener('beforeunload', unloadWarning);
+      window.removeEventListener('unload', cancelWhenUnloaded);
+      reportStatus('Paste is cached on the server and can be downloaded after this page is ' +
+        'closed, until the cache needs the room or the server restarts');
+    }
+  }
+
+  function uploadSuccess (xhr) {
+    reportStatus('Upload ok: "' + xhr.responseText + '"')
+    countUpload();
     errors = 0;
 
     // get it ready to go again
     curxhr = upload(value, id, secret, uploadSuccess, uploadError);
   }
 
+  // Wait before trying again, so that we're still around to re-attach if the server is
+  // restarting. It tells us how long to wait when it's shutting down.
+  function backoff () {
+    return Math.min(500 * Math.pow(2, errors), 30000);
+  }
+
+  function retryDelay (xhr) {
+    var retryAfter = parseInt(xhr.getResponseHeader('Retry-After'), 10);
+    if (retryAfter > 0) {
+      return retryAfter * 1000;
+    }
+    return backoff();
+  }
+
+  function stopEvents () {
+    if (curevents) {
+      curevents.close();
+      curevents = null;
+    }
+  }
+
+  // Progress reports from the server, so we hear about downloads and expiry even between uploads.
+  function watchEvents () {
+    curevents = new EventSource(basePath + '/1/id/events?id=' + id + '&secret=' + secret);
+
+    curevents.addEventListener('downloader', function () {
+      reportStatus('Downloader arrived');
+    });
+    curevents.addEventListener('completed', function (e) {
+      reportStatus('Download completed, ' + JSON.parse(e.data).bytes + ' bytes');
+    });
+    curevents.addEventListener('aborted', function (e) {
+      reportStatus('Download aborted after ' + JSON.parse(e.data).bytes + ' bytes');
+    });
+    curevents.addEventListener('expiring', function (e) {
+      reportStatus('Id expires in ' + JSON.parse(e.data).seconds + ' seconds without downloads');
+    });
+    curevents.addEventListener('retired', function (e) {
+      if (JSON.parse(e.data).reason !== 'expired') {
+        return;
+      }
+      reportStatus('Id expired.');
+      stopEvents();
+      if (curxhr) {
+        curxhr.abort();
+        curxhr = null;
+      }
+      if (cursocket) {
+        closeSocket(cursocket);
+        cursocket = null;
+      }
+      clearTimeout(retryTimer);
+      retryTimer = null;
+      reset();
+    });
+  }
+
+  function startSocket () {
+    var bytes = new TextEncoder().encode(value);
+    var opened = false;
+    var scheme = window.location.protocol === 'https:' ? 'wss:' : 'ws:';
+
+    var socket = new WebSocket(scheme + '//' + window.location.host + basePath +
+                               '/1/file/socket?id=' + id + '&secret=' + secret);
+    socket.binaryType = 'arraybuffer';
+    cursocket = socket;
+
+    socket.onopen = function () {
+      opened = true;
+      errors = 0;
+      reportStatus('Waiting for downloads');
+    };
+
+    socket.onmessage = function (e) {
+      var message = JSON.parse(e.data);
+      if (message.type === 'request') {
+        reportStatus('Sending transfer ' + message.transfer);
+        sendTransfer(socket, bytes, message);
+      } else if (message.type === 'done') {
+        reportStatus('Transfer ' + message.transfer + ' ok');
+        countUpload();
+      } else if (message.type === 'aborted') {
+        reportStatus('Transfer ' + message.transfer + ' aborted by downloader');
+      }
+    };
+
+    socket.onclose = function () {
+      cursocket = null;
+
+      if (!opened) {
+        // maybe a proxy in the way, fall back to plain uploads
+        reportStatus('Socket unavailable, uploading instead');
+        curxhr = upload(value, id, secret, uploadSuccess, uploadError);
+        return;
+      }
+
+      errors += 1;
+      if (errors < 20) {
+        reportStatus('Socket closed, reconnecting');
+        retryTimer = setTimeout(function () {
+          retryTimer = null;
+          startSocket();
+        }, backoff());
+      } else {
+        reportStatus('Too many errors, giving up');
+        stopEvents();
+        reset();
+      }
+    };
+  }
+
   function uploadError (type, xhr) {
-    if (type === 'http-error') {
+    if (type === 'http-error' && xhr.status == 404) {
+      reportStatus('Upload failed, id is no longer available.');
+      stopEvents();
+      reset();
+      return;
+    } else if (type === 'http-error') {
       reportStatus('Upload failed with HTTP error ' + xhr.status + ', ' + xhr.responseText);
     } else if (type === 'abort') {
       reportStatus('Upload aborted.');
@@ -174,32 +366,67 @@ submit.onclick = function onclickSubmit () {
 
     if (errors < 20) {
       // try to start a new upload anyway
-      // TODO check what the error actually was
-      curxhr = upload(value, id, secret, uploadSuccess, uploadError);
+      curxhr = null;
+      retryTimer = setTimeout(function () {
+        retryTimer = null;
+        curxhr = upload(value, id, secret, uploadSuccess, uploadError);
+      }, retryDelay(xhr));
     } else {
       reportStatus('Too many errors, giving up');
+      stopEvents();
       reset();
     }
   }
 
   requestId(
     value,
+    cache,
     function requestIdLoaded (xhr) {
-      var url = window.location;
       var parts = xhr.responseText.split(',');
       id = parts[0];
       secret = parts[1];
       uploads = 0;
       uploadmeter.innerText = '0';
 
-      link.value = url.protocol + '//' + url.host + '/1/file/download?id=' + id;
+      // the landing page, which shows what the paste is before it is downloaded
+      link.value = publicUrl + '/p/' + encodeURIComponent(id);
       link.size = '' + (link.value.length);
 
+      pairbutton.onclick = function () {
+        stopPairing();
+        pairbutton.disabled = true;
+        var offer = offerPairing(id, function (code) {
+          paircode.innerText = code;
+          reportStatus('Pairing code ' + code + ' ready, type it in on the other device');
+        }, function () {
+          reportStatus('Other device joined with the pairing code');
+        });
+        pairing = offer;
+        offer.done.then(function () {
+          reportStatus('Sent the paste to the other device');
+        }, function (e) {
+          reportStatus('Pairing failed: ' + e.message);
+        }).then(function () {
+          if (pairing === offer) {
+            pairing = null;
+            paircode.innerText = '';
+            pairbutton.disabled = false;
+          }
+        });
+      };
+
       window.addEventListener('beforeunload', unloadWarning);
       window.addEventListener('unload', cancelWhenUnloaded);
 
       reportStatus('Got id');
-      curxhr = upload(value, id, secret, uploadSuccess, uploadError);
+      if (window.EventSource) {
+        watchEvents();
+      }
+      if (window.WebSocket && window.TextEncoder) {
+        startSocket();
+      } else {
+        curxhr = upload(value, id, secret, uploadSuccess, uploadError);
+      }
     },
     function requestIdError (type, xhr) {
       // TODO retry?
@@ -0,0 +1,268 @@
+use futures::future;
+use hyper::client::HttpConnector;
+use hyper::header::{self, HeaderName, HeaderValue};
+use hyper::rt::Future;
+use hyper::upgrade::OnUpgrade;
+use hyper::{Body, Client, Request, Response, StatusCode, Uri};
+use std::collections::HashMap;
+use tokio::io::{self, AsyncRead};
+use url;
+
+use error::ApiError;
+use {config, path_id, Bod, BoxFut, RendezvousPayload, TYPE_TEXT};
+
+// Set on requests passed on to another node, so a misconfigured cluster can't bounce a request
+// between nodes forever. It doesn't stop the request being routed, so clients can't use it to get
+// at the wrong node.
+static FORWARDED: &str = "x-rendezvous-forwarded";
+
+// Ids handed out by a node in a cluster look like "<node>.<token>", so any node can tell which one
+// is holding the paste.
+pub struct Cluster {
+    node_id: Option<String>,
+    nodes: HashMap<String, String>,
+    redirect: bool,
+    client: Client<HttpConnector>,
+}
+
+impl Cluster {
+    pub fn new(node_id: Option<String>, nodes: HashMap<String, String>, redirect: bool) -> Cluster {
+        Cluster {
+            node_id,
+            nodes: nodes
+                .into_iter()
+                .map(|(node, url)| (node, url.trim_end_matches('/').to_owned()))
+                .collect(),
+            redirect,
+            client: Client::new(),
+        }
+    }
+
+    pub fn qualify_id(&self, token: String) -> String {
+        match self.node_id {
+            Some(ref node_id) => format!("{}.{}", node_id, token),
+            None => token,
+        }
+    }
+
+    // The base URL of the node owning the paste named in the request, if that isn't this node.
+    // Ids for unknown nodes are left for this node to turn away.
+    fn owner<T>(&self, req: &Request<T>) -> Option<&str> {
+        self.node_id.as_ref()?;
+
+        // landing pages and the v2 API carry the id in the path rather than the query
+        let id = match path_id(req.uri().path()) {
+            Some(id) => id,
+            None => {
+                let query = req.uri().query()?;
+                url::form_urlencoded::parse(query.as_ref())
+                    .find(|(k, _)| k == "id")
+                    .map(|(_, v)| v.into_owned())?
+            }
+        };
+        let node = &id[..id.find('.')?];
+
+        if Some(node) == self.node_id.as_deref() {
+            None
+        } else {
+            self.nodes.get(node).map(String::as_str)
+        }
+    }
+
+    // Where the request should go instead, if it names a paste held by another node.
+    pub fn route<T>(&self, req: &Request<T>) -> Option<String> {
+        let base = self.owner(req)?;
+        let path = req
+            .uri()
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/");
+        Some(format!("{}{}", base, path))
+    }
+
+    // Hand the request to the owning node, either by redirecting the client there or by relaying
+    // it ourselves.
+    pub fn forward(&self, req: Request<Body>, target: String) -> BoxFut {
+        if self.redirect {
+            return Box::new(future::ok(
+                Response::builder()
+                    .status(StatusCode::TEMPORARY_REDIRECT)
+                    .header(header::LOCATION, target)
+                    .header(header::CONTENT_TYPE, TYPE_TEXT)
+                    .body(Bod(Body::empty()))
+                    .unwrap(),
+            ));
+        }
+
+        // Nodes only pass on requests for pastes they are told are held by the node they pass
+        // them to, so a request that was passed on once already is either a sign that the nodes
+        // disagree about where a paste is, or forged.
+        if req.headers().contains_key(FORWARDED) {
+            eprintln!(
+                "Not passing on a request that was passed on already, to {}",
+                target
+            );
+            return Box::new(future::ok(bad_gateway()));
+        }
+
+        let (mut parts, mut body) = req.into_parts();
+
+        // An uploader's WebSocket: once both sides have switched protocols, shuttle bytes between
+        // them.
+        let upgrade = if parts.headers.contains_key(header::UPGRADE) {
+            let upgrade = body.on_upgrade();
+            body = Body::empty();
+            Some(upgrade)
+        } else {
+            None
+        };
+
+        parts.uri = match target.parse::<Uri>() {
+            Ok(uri) => uri,
+            Err(_) => return Box::new(future::ok(bad_gateway())),
+        };
+        // the client fills in the Host for the new target, and the node there is told the old one
+        // for its links, unless a proxy in front has already said what the client asked for
+        let proxied = parts.headers.contains_key(header::FORWARDED)
+            || parts.headers.contains_key("x-forwarded-host");
+        if let Some(host) = parts.headers.remove(header::HOST) {
+            if !proxied {
+                let proto = if config().tls_cert_file.is_some() {
+                    "https"
+                } else {
+                    "http"
+                };
+                parts
+                    .headers
+                    .insert(HeaderName::from_static("x-forwarded-host"), host);
+                parts.headers.insert(
+                    HeaderName::from_static("x-forwarded-proto"),
+                    HeaderValue::from_static(proto),
+                );
+            }
+        }
+        parts.headers.insert(
+            HeaderName::from_static(FORWARDED),
+            HeaderValue::from_static("1"),
+        );
+
+        Box::new(self.client.request(Request::from_parts(parts, body)).then(
+            |result| match result {
+                Ok(response) => {
+                    let (mut parts, body) = response.into_parts();
+                    match upgrade {
+                        Some(upgrade) if parts.status == StatusCode::SWITCHING_PROTOCOLS => {
+                            bridge(upgrade, body);
+                            return Ok(Response::from_parts(parts, Bod(Body::empty())));
+                        }
+                        _ => {}
+                    }
+                    parts.headers.remove(header::CONNECTION);
+                    parts.headers.remove(header::TRANSFER_ENCODING);
+                    Ok(Response::from_parts(parts, Bod(body)))
+                }
+                Err(e) => {
+                    eprintln!("Error forwarding request: {}", e);
+                    Ok(bad_gateway())
+                }
+            },
+        ))
+    }
+}
+
+fn bridge(downstream: OnUpgrade, upstream: Body) {
+    hyper::rt::spawn(
+        downstream
+            .join(upstream.on_upgrade())
+            .map_err(|e| eprintln!("Error forwarding upgrade: {}", e))
+            .and_then(|(downstream, upstream)| {
+                let (down_read, down_write) = downstream.split();
+                let (up_read, up_write) = upstream.split();
+                io::copy(down_read, up_write)
+                    .join(io::copy(up_read, down_write))
+                    .map(|_| ())
+                    .map_err(|_| ())
+            }),
+    );
+}
+
+fn bad_gateway() -> Response<RendezvousPayload> {
+    ApiError::NodeUnavailable.response()
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::Future;
+    use hyper::header::{self, HeaderName};
+    use hyper::{Body, Client, Request, StatusCode};
+    use std::collections::HashMap;
+    use tokio::runtime::Runtime;
+
+    use super::{Cluster, FORWARDED};
+    use testing;
+
+    #[test]
+    fn requests_reach_the_node_holding_the_paste() {
+        let mut runtime = Runtime::new().unwrap();
+        let (listener_a, a) = testing::listen();
+        let (listener_b, b) = testing::listen();
+        let nodes: HashMap<String, String> = vec![
+            ("a".to_owned(), format!("http://{}", a)),
+            ("b".to_owned(), format!("http://{}", b)),
+        ]
+        .into_iter()
+        .collect();
+        testing::serve(
+            &mut runtime,
+            listener_a,
+            Cluster::new(Some("a".to_owned()), nodes.clone(), false),
+        );
+        testing::serve(
+            &mut runtime,
+            listener_b,
+            Cluster::new(Some("b".to_owned()), nodes, false),
+        );
+        let client = Client::new();
+
+        let create = Request::post(format!("http://{}/2/pastes?length=5", a))
+            .body(Body::empty())
+            .unwrap();
+        let (status, created) = runtime.block_on(testing::fetch(&client, create)).unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        let id = testing::json_field(&created, "id");
+        let secret = testing::json_field(&created, "secret");
+        assert!(id.starts_with("a."), "{}", id);
+
+        // both sides go through b, which passes them on to a
+        let upload = Request::put(format!("http://{}/2/pastes/{}", b, id))
+            .header(header::AUTHORIZATION, format!("Bearer {}", secret))
+            .header(header::CONTENT_LENGTH, 5)
+            .body(Body::from("hello"))
+            .unwrap();
+        let download = Request::get(format!("http://{}/2/pastes/{}", b, id))
+            .body(Body::empty())
+            .unwrap();
+        let ((upload_status, _), (download_status, content)) = runtime
+            .block_on(testing::fetch(&client, upload).join(testing::fetch(&client, download)))
+            .unwrap();
+        assert_eq!(upload_status, StatusCode::OK);
+        assert_eq!(download_status, StatusCode::OK);
+        assert_eq!(content, "hello");
+
+        // claiming to have been passed on already doesn't keep a request at the wrong node
+        let forged = Request::delete(format!("http://{}/2/pastes/{}", b, id))
+            .header(HeaderName::from_static(FORWARDED), "1")
+            .header(header::AUTHORIZATION, format!("Bearer {}", secret))
+            .body(Body::empty())
+            .unwrap();
+        let (status, _) = runtime.block_on(testing::fetch(&client, forged)).unwrap();
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+
+        let retire = Request::delete(format!("http://{}/2/pastes/{}", b, id))
+            .header(header::AUTHORIZATION, format!("Bearer {}", secret))
+            .body(Body::empty())
+            .unwrap();
+        let (status, _) = runtime.block_on(testing::fetch(&client, retire)).unwrap();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+    }
+}
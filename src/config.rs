@@ -0,0 +1,444 @@
+// Where the configuration comes from: the built-in defaults, then the file given on the command
+// line, then RENDEZVOUS_* environment variables, then --bind. Anything wrong with it is reported
+// along with the key it was given for.
+
+use hyper::Uri;
+use std::env;
+use std::ffi::OsString;
+use std::fmt::Display;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process;
+use toml::value::{Table, Value};
+
+use Config;
+
+pub static DEFAULTS: &str = include_str!("defaults.toml");
+
+static ENV_PREFIX: &str = "RENDEZVOUS_";
+
+pub static USAGE: &str = "\
+Usage: rendezvous [OPTIONS] [CONFIG_FILE]
+
+Options:
+  -c, --config FILE         Read settings from FILE, as in --print-default-config
+  -b, --bind ADDRESS        IP address and port to bind to, overriding bind
+      --check-config        Check the configuration and exit
+      --print-default-config
+                            Print the default configuration, with comments, and exit
+  -V, --version             Print the version and exit
+  -h, --help                Print this help and exit
+
+Any setting can also be given as an environment variable named RENDEZVOUS_ and the setting in
+capitals, like RENDEZVOUS_TIMEOUT_SECS=600. Lists are separated by commas, and nodes are given as
+name=url pairs. These override the configuration file, and --bind overrides both.
+";
+
+#[derive(Default)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub struct Args {
+    pub config_file: Option<OsString>,
+    pub bind: Option<String>,
+    pub check_config: bool,
+    pub print_default_config: bool,
+    pub version: bool,
+    pub help: bool,
+}
+
+lazy_static! {
+    // Tests are run with the test harness's arguments, so get the defaults instead.
+    pub static ref ARGS: Args = if cfg!(test) {
+        Args::default()
+    } else {
+        parse_args(env::args_os().skip(1)).unwrap_or_else(|e| {
+            eprintln!("{}\nSee --help for the options.", e);
+            process::exit(2);
+        })
+    };
+}
+
+fn parse_args<I: Iterator<Item = OsString>>(mut args: I) -> Result<Args, String> {
+    let mut parsed = Args::default();
+
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.to_str() {
+            Some(a) if a.starts_with("--") && a.contains('=') => {
+                let (flag, value) = a.split_at(a.find('=').unwrap());
+                (flag.to_owned(), Some(OsString::from(&value[1..])))
+            }
+            Some(a) if a.starts_with('-') && a != "-" => (a.to_owned(), None),
+            _ => {
+                // A bare argument is the configuration file, as it always has been.
+                if parsed.config_file.is_some() {
+                    return Err(format!("Unexpected argument {}", arg.to_string_lossy()));
+                }
+                parsed.config_file = Some(arg);
+                continue;
+            }
+        };
+
+        let value = |args: &mut I| {
+            inline
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("{} needs a value", flag))
+        };
+        match flag.as_str() {
+            "-c" | "--config" => parsed.config_file = Some(value(&mut args)?),
+            "-b" | "--bind" => {
+                let bind = value(&mut args)?;
+                let bind = bind
+                    .into_string()
+                    .map_err(|_| format!("{} isn't valid UTF-8", flag))?;
+                parsed.bind = Some(bind);
+            }
+            "--check-config" => parsed.check_config = true,
+            "--print-default-config" => parsed.print_default_config = true,
+            "-V" | "--version" => parsed.version = true,
+            "-h" | "--help" => parsed.help = true,
+            _ => return Err(format!("Unknown option {}", flag)),
+        }
+        if inline.is_some() && !flag_takes_value(&flag) {
+            return Err(format!("{} doesn't take a value", flag));
+        }
+    }
+
+    Ok(parsed)
+}
+
+fn flag_takes_value(flag: &str) -> bool {
+    matches!(flag, "-c" | "--config" | "-b" | "--bind")
+}
+
+// The configuration from every source, checked.
+pub fn load(args: &Args) -> Result<Config, String> {
+    let text = match args.config_file {
+        Some(ref path) => fs::read_to_string(path).map_err(|e| {
+            format!(
+                "Error reading configuration file {}: {}",
+                Path::new(path).display(),
+                e
+            )
+        })?,
+        None => String::new(),
+    };
+
+    // The file on its own first, for errors with line numbers.
+    let mut table = toml::from_str::<Config>(&text)
+        .and_then(|_| toml::from_str::<Table>(&text))
+        .map_err(|e| format!("Error in configuration file: {}", e))?;
+
+    let defaults = Value::try_from(toml::from_str::<Config>("").unwrap()).unwrap();
+    for (name, value) in env::vars_os() {
+        let name = match name.into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        if !name.starts_with(ENV_PREFIX) {
+            continue;
+        }
+        let key = name[ENV_PREFIX.len()..].to_lowercase();
+        let value = value
+            .into_string()
+            .map_err(|_| format!("{} isn't valid UTF-8", name))
+            .and_then(|value| env_value(&value, defaults.get(&key)))
+            .map_err(|e| format!("Error in {}: {}", name, e))?;
+        table.insert(key, value);
+    }
+
+    if let Some(ref bind) = args.bind {
+        table.insert("bind".to_owned(), Value::String(bind.clone()));
+    }
+
+    let mut config = Value::Table(table)
+        .try_into::<Config>()
+        .map_err(|e| format!("Error in configuration: {}", e))?;
+    if config.timeout_scan_interval_secs.is_some() {
+        eprintln!("timeout_scan_interval_secs is no longer used and can be removed");
+    }
+    normalize(&mut config);
+    validate(&config).map_err(|e| format!("Invalid configuration: {}", e))?;
+    Ok(config)
+}
+
+// An environment variable's value, read as the type of the setting it is for.
+fn env_value(value: &str, default: Option<&Value>) -> Result<Value, String> {
+    let list = || value.split(',').map(str::trim).filter(|v| !v.is_empty());
+
+    match default {
+        Some(Value::Integer(_)) => value
+            .trim()
+            .parse()
+            .map(Value::Integer)
+            .map_err(|_| format!("{:?} isn't a whole number", value)),
+        Some(Value::Boolean(_)) => match value.trim() {
+            "true" | "1" => Ok(Value::Boolean(true)),
+            "false" | "0" => Ok(Value::Boolean(false)),
+            _ => Err(format!("{:?} should be true or false", value)),
+        },
+        Some(Value::Array(_)) => Ok(Value::Array(
+            list().map(|v| Value::String(v.to_owned())).collect(),
+        )),
+        Some(Value::Table(_)) => list()
+            .map(|pair| match pair.find('=') {
+                Some(i) => Ok((
+                    pair[..i].trim().to_owned(),
+                    Value::String(pair[i + 1..].trim().to_owned()),
+                )),
+                None => Err(format!("{:?} should be name=value", pair)),
+            })
+            .collect::<Result<Table, String>>()
+            .map(Value::Table),
+        // Strings, and settings that are unset by default, which are all strings.
+        _ => Ok(Value::String(value.to_owned())),
+    }
+}
+
+// "/paste" however it was written, or empty at the root, and public_url without a trailing "/".
+fn normalize(config: &mut Config) {
+    let base_path = config.base_path.trim_matches('/');
+    config.base_path = if base_path.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", base_path)
+    };
+    config.public_url = config
+        .public_url
+        .take()
+        .map(|u| u.trim_end_matches('/').to_owned());
+}
+
+// "key = value: what's wrong with it", with the value as it would be written in the file.
+fn invalid<V: Into<Value>>(key: &str, value: V, problem: impl Display) -> String {
+    format!("{} = {}: {}", key, value.into(), problem)
+}
+
+fn validate(config: &Config) -> Result<(), String> {
+    if let Err(e) = config.bind.parse::<SocketAddr>() {
+        return Err(invalid(
+            "bind",
+            config.bind.as_str(),
+            format!("{}, should be like 127.0.0.1:3000", e),
+        ));
+    }
+
+    if config.tls_cert_file.is_some() != config.tls_key_file.is_some() {
+        return Err("tls_cert_file and tls_key_file must be set together".to_owned());
+    }
+
+    if let Some(ref url) = config.public_url {
+        if !is_absolute_url(url) {
+            return Err(invalid(
+                "public_url",
+                url.as_str(),
+                "should be a full URL like https://tools.example.com/paste",
+            ));
+        }
+    }
+
+    if let Some(ref dir) = config.assets_dir {
+        if !Path::new(dir).is_dir() {
+            return Err(invalid("assets_dir", dir.as_str(), "not a directory"));
+        }
+    }
+
+    let at_least_one = [
+        ("timeout_secs", config.timeout_secs),
+        ("token_length", config.token_length as u64),
+        ("id_words", config.id_words as u64),
+        ("wait_timeout_secs", config.wait_timeout_secs),
+        (
+            "transfer_idle_timeout_secs",
+            config.transfer_idle_timeout_secs,
+        ),
+        ("pairing_timeout_secs", config.pairing_timeout_secs),
+        ("state_save_interval_secs", config.state_save_interval_secs),
+    ];
+    for (key, value) in at_least_one.iter() {
+        if *value == 0 {
+            return Err(invalid(key, 0, "should be at least 1"));
+        }
+    }
+    // with no room to get ahead, even a lone downloader would wait on itself and be cut off
+    if config.broadcast_window_ms > 0 && config.broadcast_buffer_bytes == 0 {
+        return Err(invalid(
+            "broadcast_buffer_bytes",
+            0,
+            "should be at least 1 while broadcast_window_ms is set",
+        ));
+    }
+
+    if let Some(ref id) = config.node_id {
+        if id.is_empty() || id.contains(|c: char| c == '.' || c == '/' || c.is_whitespace()) {
+            return Err(invalid(
+                "node_id",
+                id.as_str(),
+                "should be a name without dots, slashes or spaces",
+            ));
+        }
+    }
+
+    for (name, url) in &config.nodes {
+        if !is_absolute_url(url) {
+            return Err(invalid(
+                &format!("nodes.{}", name),
+                url.as_str(),
+                "should be a URL like http://127.0.0.1:3001",
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn is_absolute_url(url: &str) -> bool {
+    url.parse::<Uri>()
+        .is_ok_and(|u| u.scheme_part().is_some() && u.authority_part().is_some())
+}
+
+// Settings only read at startup, which a reload leaves as they were.
+static RESTART_ONLY: &[&str] = &[
+    "bind",
+    "http2",
+    "tls_cert_file",
+    "tls_key_file",
+    "assets_dir",
+    "assets_reload",
+    "id_scheme",
+    "id_words",
+    "cache_size_bytes",
+    "state_file",
+    "state_save_interval_secs",
+    "node_id",
+    "nodes",
+    "cluster_redirect",
+];
+
+fn to_table(config: &Config) -> Table {
+    match Value::try_from(config) {
+        Ok(Value::Table(table)) => table,
+        _ => unreachable!("a Config is a table"),
+    }
+}
+
+// The configuration loaded again, for SIGHUP, and what changed, to be logged once it is in use.
+pub fn reload(old: &Config) -> Result<(Config, Vec<String>), String> {
+    let old = to_table(old);
+    let mut new = to_table(&load(&ARGS)?);
+
+    let mut keys: Vec<&String> = old.keys().chain(new.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    let mut changes = Vec::new();
+    for key in keys {
+        let (was, is) = (old.get(key), new.get(key));
+        if was == is {
+            continue;
+        }
+        let show = |v: Option<&Value>| v.map_or("unset".to_owned(), Value::to_string);
+        let mut change = format!("{}: {} -> {}", key, show(was), show(is));
+        if RESTART_ONLY.contains(&key.as_str()) {
+            change.push_str(" (needs a restart, keeping the old value)");
+        }
+        changes.push(change);
+    }
+    for key in RESTART_ONLY {
+        match old.get(*key) {
+            Some(value) => new.insert((*key).to_owned(), value.clone()),
+            None => new.remove(*key),
+        };
+    }
+
+    let new = Value::Table(new)
+        .try_into()
+        .map_err(|e| format!("Error in configuration: {}", e))?;
+    Ok((new, changes))
+}
+
+pub fn log_changes(changes: &[String]) {
+    if changes.is_empty() {
+        eprintln!("Reloaded configuration, nothing changed");
+    } else {
+        eprintln!("Reloaded configuration:");
+        for change in changes {
+            eprintln!("  {}", change);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Args, String> {
+        parse_args(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn flags_take_values_separately_or_inline() {
+        let parsed = args(&["-b", "0.0.0.0:80", "--config=a.toml", "--check-config"]).unwrap();
+        assert_eq!(parsed.bind.as_deref(), Some("0.0.0.0:80"));
+        assert_eq!(parsed.config_file, Some(OsString::from("a.toml")));
+        assert!(parsed.check_config && !parsed.help);
+    }
+
+    #[test]
+    fn a_bare_argument_is_the_config_file() {
+        let parsed = args(&["rendezvous.toml", "-V"]).unwrap();
+        assert_eq!(parsed.config_file, Some(OsString::from("rendezvous.toml")));
+        assert!(parsed.version);
+        assert!(args(&["a.toml", "b.toml"]).is_err());
+    }
+
+    #[test]
+    fn bad_flags_are_refused() {
+        assert!(args(&["--frobnicate"]).is_err());
+        assert!(args(&["--bind"]).is_err());
+        assert!(args(&["--help=yes"]).is_err());
+    }
+
+    #[test]
+    fn env_values_take_the_type_of_the_default() {
+        let value = |v: &str, default: Value| env_value(v, Some(&default));
+
+        assert_eq!(value(" 600 ", Value::Integer(0)), Ok(Value::Integer(600)));
+        assert!(value("ten", Value::Integer(0)).is_err());
+        assert_eq!(value("1", Value::Boolean(false)), Ok(Value::Boolean(true)));
+        assert!(value("yes", Value::Boolean(false)).is_err());
+        assert_eq!(
+            value("a, b,,", Value::Array(vec![])),
+            Ok(Value::Array(vec![
+                Value::String("a".to_owned()),
+                Value::String("b".to_owned())
+            ]))
+        );
+        assert_eq!(
+            env_value("cert.pem", None),
+            Ok(Value::String("cert.pem".to_owned()))
+        );
+    }
+
+    #[test]
+    fn env_nodes_are_name_url_pairs() {
+        let nodes = env_value(
+            "a=http://127.0.0.1:3000, b = http://127.0.0.1:3001",
+            Some(&Value::Table(Table::new())),
+        )
+        .unwrap();
+        let nodes = nodes.as_table().unwrap();
+        assert_eq!(nodes["a"].as_str(), Some("http://127.0.0.1:3000"));
+        assert_eq!(nodes["b"].as_str(), Some("http://127.0.0.1:3001"));
+        assert!(env_value("a", Some(&Value::Table(Table::new()))).is_err());
+    }
+
+    #[test]
+    fn broadcasting_needs_a_buffer() {
+        let mut config = toml::from_str::<Config>("").unwrap();
+        assert!(validate(&config).is_ok());
+        config.broadcast_buffer_bytes = 0;
+        assert!(validate(&config).is_ok());
+        config.broadcast_window_ms = 100;
+        assert!(validate(&config).is_err());
+    }
+}
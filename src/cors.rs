@@ -0,0 +1,60 @@
+// Cross-origin requests, so pages on the origins in cors_allowed_origins can use the API from the
+// browser. Preflights are answered here before anything else sees them, and every response to an
+// allowed origin says so and lets its script read the headers that matter.
+
+use futures::future;
+use hyper::header::{self, HeaderValue};
+use hyper::{Body, HeaderMap, Method, Response, StatusCode};
+
+use {config, Bod, BoxFut};
+
+static ALLOW_METHODS: &str = "GET, POST, PUT, DELETE";
+// Sent by uploaders and downloaders besides the simple ones.
+static ALLOW_HEADERS: &str = "Authorization, Content-Type, Content-Encoding, Range";
+static EXPOSE_HEADERS: &str = "Content-Length, Content-Range, Content-Encoding, \
+                               Content-Disposition, ETag, X-Rendezvous-Expires, Retry-After, \
+                               Location, Allow";
+static MAX_AGE_SECS: u64 = 600;
+
+// The Origin to echo back, if it is allowed.
+pub fn allowed_origin(headers: &HeaderMap) -> Option<HeaderValue> {
+    let origin = headers.get(header::ORIGIN)?;
+    let allowed = config()
+        .cors_allowed_origins
+        .iter()
+        .any(|o| o == "*" || origin.to_str().is_ok_and(|origin| o == origin));
+    if allowed {
+        Some(origin.clone())
+    } else {
+        None
+    }
+}
+
+pub fn preflight(method: &Method, headers: &HeaderMap, origin: &HeaderValue) -> Option<BoxFut> {
+    if method != Method::OPTIONS || !headers.contains_key(header::ACCESS_CONTROL_REQUEST_METHOD) {
+        return None;
+    }
+
+    Some(Box::new(future::ok(
+        Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone())
+            .header(header::ACCESS_CONTROL_ALLOW_METHODS, ALLOW_METHODS)
+            .header(header::ACCESS_CONTROL_ALLOW_HEADERS, ALLOW_HEADERS)
+            .header(header::ACCESS_CONTROL_MAX_AGE, MAX_AGE_SECS)
+            .header(header::VARY, "origin")
+            .body(Bod(Body::empty()))
+            .unwrap(),
+    )))
+}
+
+pub fn allow<T>(mut response: Response<T>, origin: HeaderValue) -> Response<T> {
+    let headers = response.headers_mut();
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    headers.insert(
+        header::ACCESS_CONTROL_EXPOSE_HEADERS,
+        HeaderValue::from_static(EXPOSE_HEADERS),
+    );
+    headers.append(header::VARY, HeaderValue::from_static("origin"));
+    response
+}
@@ -0,0 +1,334 @@
+// Content codings on the download path. Pastes stored uncompressed are compressed on the fly for
+// downloaders that accept it, and pastes uploaded pre-compressed are passed through as they are,
+// or decompressed for downloaders that can't take them.
+
+use brotli::{CompressorWriter, DecompressorWriter};
+use flate2::write::{GzDecoder, GzEncoder};
+use flate2::Compression;
+use futures::{Async, Poll};
+use hyper::body::Payload;
+use hyper::header::{self, HeaderValue};
+use hyper::{Chunk, HeaderMap};
+use std::error::Error;
+#[cfg(debug_assertions)]
+use std::fmt;
+use std::io::{self, Write};
+use std::mem;
+
+// Fast enough to keep up with a transfer, while still getting most of the gain on text.
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+const BUFFER_SIZE: usize = 4096;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    pub fn parse(name: &str) -> Option<Encoding> {
+        match name.trim().to_ascii_lowercase().as_ref() {
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "br" => Some(Encoding::Brotli),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+        }
+    }
+
+    // Everything Accept-Encoding may call it.
+    fn names(self) -> &'static [&'static str] {
+        match self {
+            Encoding::Gzip => &["gzip", "x-gzip"],
+            Encoding::Brotli => &["br"],
+        }
+    }
+}
+
+// The q-value Accept-Encoding gives a coding going by any of names, where an explicit entry beats
+// "*" and no entry at all means it isn't accepted.
+fn quality(headers: &HeaderMap, names: &[&str]) -> f32 {
+    let mut wildcard = None;
+    for value in headers.get_all(header::ACCEPT_ENCODING) {
+        let value = match value.to_str() {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        for item in value.split(',') {
+            let mut params = item.split(';');
+            let coding = params.next().unwrap_or("").trim();
+            let q = params
+                .filter_map(|p| {
+                    let p = p.trim();
+                    if p.starts_with("q=") || p.starts_with("Q=") {
+                        p[2..].trim().parse::<f32>().ok()
+                    } else {
+                        None
+                    }
+                })
+                .next()
+                .unwrap_or(1.0);
+
+            if names.iter().any(|name| coding.eq_ignore_ascii_case(name)) {
+                return q;
+            }
+            if coding == "*" {
+                wildcard = Some(q);
+            }
+        }
+    }
+    wildcard.unwrap_or(0.0)
+}
+
+pub fn accepts(headers: &HeaderMap, encoding: Encoding) -> bool {
+    quality(headers, encoding.names()) > 0.0
+}
+
+// How a download is sent, given the coding it was stored with.
+#[derive(Clone, Copy)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub enum Coding {
+    Stored(Option<Encoding>),
+    Compress(Encoding),
+    Decompress(Encoding),
+}
+
+// Brotli is preferred over gzip when both are accepted equally. Compressing is only considered
+// when the caller allows it, as it doesn't for ranges, which refer to the content as stored.
+pub fn negotiate(stored: Option<Encoding>, headers: &HeaderMap, compress: bool) -> Coding {
+    match stored {
+        Some(encoding) if accepts(headers, encoding) => Coding::Stored(stored),
+        Some(encoding) => Coding::Decompress(encoding),
+        None if compress => {
+            let br = quality(headers, Encoding::Brotli.names());
+            let gzip = quality(headers, Encoding::Gzip.names());
+            if br > 0.0 && br >= gzip {
+                Coding::Compress(Encoding::Brotli)
+            } else if gzip > 0.0 {
+                Coding::Compress(Encoding::Gzip)
+            } else {
+                Coding::Stored(None)
+            }
+        }
+        None => Coding::Stored(None),
+    }
+}
+
+impl Coding {
+    // The Content-Encoding the downloader is sent.
+    pub fn content_encoding(self) -> Option<HeaderValue> {
+        match self {
+            Coding::Stored(encoding) => encoding.map(|e| HeaderValue::from_static(e.name())),
+            Coding::Compress(encoding) => Some(HeaderValue::from_static(encoding.name())),
+            Coding::Decompress(_) => None,
+        }
+    }
+}
+
+enum Coder {
+    GzipEncoder(GzEncoder<Vec<u8>>),
+    GzipDecoder(GzDecoder<Vec<u8>>),
+    BrotliEncoder(Box<CompressorWriter<Vec<u8>>>),
+    BrotliDecoder(Box<DecompressorWriter<Vec<u8>>>),
+}
+
+impl Coder {
+    // Whatever output the data produced. The coders hold on to some until they have enough.
+    fn write(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let output = match self {
+            Coder::GzipEncoder(c) => {
+                c.write_all(data)?;
+                c.get_mut()
+            }
+            Coder::GzipDecoder(c) => {
+                c.write_all(data)?;
+                c.get_mut()
+            }
+            Coder::BrotliEncoder(c) => {
+                c.write_all(data)?;
+                c.get_mut()
+            }
+            Coder::BrotliDecoder(c) => {
+                c.write_all(data)?;
+                c.get_mut()
+            }
+        };
+        Ok(mem::take(output))
+    }
+
+    // The rest of the output, once there is no more data. Fails for a truncated compressed stream.
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Coder::GzipEncoder(c) => c.finish(),
+            Coder::GzipDecoder(c) => c.finish(),
+            Coder::BrotliEncoder(c) => Ok(c.into_inner()),
+            Coder::BrotliDecoder(mut c) => {
+                c.close()?;
+                Ok(mem::take(c.get_mut()))
+            }
+        }
+    }
+}
+
+// A payload run through a compressor or decompressor. Its length isn't known up front.
+pub struct Encoded<P> {
+    inner: P,
+    // None once finished
+    coder: Option<Coder>,
+}
+
+#[cfg(debug_assertions)]
+impl<P> fmt::Debug for Encoded<P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Encoded")
+            .field("finished", &self.coder.is_none())
+            .finish()
+    }
+}
+
+impl<P> Encoded<P> {
+    pub fn compress(inner: P, encoding: Encoding) -> Encoded<P> {
+        let coder = match encoding {
+            Encoding::Gzip => {
+                Coder::GzipEncoder(GzEncoder::new(Vec::new(), Compression::default()))
+            }
+            Encoding::Brotli => Coder::BrotliEncoder(Box::new(CompressorWriter::new(
+                Vec::new(),
+                BUFFER_SIZE,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            ))),
+        };
+        Encoded {
+            inner,
+            coder: Some(coder),
+        }
+    }
+
+    pub fn decompress(inner: P, encoding: Encoding) -> Encoded<P> {
+        let coder = match encoding {
+            Encoding::Gzip => Coder::GzipDecoder(GzDecoder::new(Vec::new())),
+            Encoding::Brotli => {
+                Coder::BrotliDecoder(Box::new(DecompressorWriter::new(Vec::new(), BUFFER_SIZE)))
+            }
+        };
+        Encoded {
+            inner,
+            coder: Some(coder),
+        }
+    }
+}
+
+impl<P> Payload for Encoded<P>
+where
+    P: Payload<Data = Chunk>,
+    P::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    type Data = Chunk;
+    type Error = Box<dyn Error + Send + Sync>;
+
+    fn poll_data(&mut self) -> Poll<Option<Chunk>, Self::Error> {
+        loop {
+            if self.coder.is_none() {
+                return Ok(Async::Ready(None));
+            }
+
+            let output = match self.inner.poll_data().map_err(Into::into)? {
+                Async::Ready(Some(chunk)) => self.coder.as_mut().unwrap().write(&chunk)?,
+                Async::Ready(None) => self.coder.take().unwrap().finish()?,
+                Async::NotReady => return Ok(Async::NotReady),
+            };
+            if !output.is_empty() {
+                return Ok(Async::Ready(Some(Chunk::from(output))));
+            }
+        }
+    }
+
+    fn poll_trailers(&mut self) -> Poll<Option<HeaderMap>, Self::Error> {
+        Ok(Async::Ready(None))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.coder.is_none()
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accepting(values: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(header::ACCEPT_ENCODING, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn q_values_are_read_per_coding() {
+        let headers = accepting(&["gzip;q=0.5, br ; Q=0.8", "identity"]);
+        assert_eq!(quality(&headers, &["gzip"]), 0.5);
+        assert_eq!(quality(&headers, &["br"]), 0.8);
+        assert_eq!(quality(&headers, &["identity"]), 1.0);
+        assert_eq!(quality(&headers, &["deflate"]), 0.0);
+    }
+
+    #[test]
+    fn an_explicit_entry_beats_the_wildcard() {
+        let headers = accepting(&["*;q=0.3, gzip;q=0"]);
+        assert_eq!(quality(&headers, Encoding::Gzip.names()), 0.0);
+        assert_eq!(quality(&headers, Encoding::Brotli.names()), 0.3);
+        assert!(!accepts(&headers, Encoding::Gzip));
+        assert!(accepts(&headers, Encoding::Brotli));
+
+        let coding = negotiate(None, &headers, true);
+        assert!(matches!(coding, Coding::Compress(Encoding::Brotli)));
+    }
+
+    #[test]
+    fn x_gzip_counts_as_gzip() {
+        assert!(accepts(&accepting(&["x-gzip"]), Encoding::Gzip));
+    }
+
+    #[test]
+    fn brotli_wins_ties() {
+        let coding = negotiate(None, &accepting(&["gzip, br"]), true);
+        assert!(matches!(coding, Coding::Compress(Encoding::Brotli)));
+
+        let coding = negotiate(None, &accepting(&["gzip, br;q=0.5"]), true);
+        assert!(matches!(coding, Coding::Compress(Encoding::Gzip)));
+    }
+
+    #[test]
+    fn nothing_is_compressed_unless_allowed_and_accepted() {
+        let coding = negotiate(None, &accepting(&["gzip, br"]), false);
+        assert!(matches!(coding, Coding::Stored(None)));
+
+        let coding = negotiate(None, &accepting(&["identity"]), true);
+        assert!(matches!(coding, Coding::Stored(None)));
+        assert!(coding.content_encoding().is_none());
+    }
+
+    #[test]
+    fn stored_content_is_decompressed_only_when_not_accepted() {
+        let coding = negotiate(Some(Encoding::Gzip), &accepting(&["gzip"]), false);
+        assert!(matches!(coding, Coding::Stored(Some(Encoding::Gzip))));
+        assert_eq!(coding.content_encoding().unwrap(), "gzip");
+
+        let coding = negotiate(Some(Encoding::Brotli), &accepting(&["gzip"]), true);
+        assert!(matches!(coding, Coding::Decompress(Encoding::Brotli)));
+        assert!(coding.content_encoding().is_none());
+    }
+}
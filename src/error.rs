@@ -0,0 +1,396 @@
+// Everything a request can fail with. Handlers answer with ApiError::response(), which has a plain
+// text body, and the error itself kept with the response so that once it is known what the client
+// prefers, it can be rendered again as JSON, e.g. {"error":"bad_secret","message":"Bad secret"},
+// or as an HTML page for browsers. Automation should branch on the code, the messages may change.
+
+use hyper::header::{self, HeaderValue};
+use hyper::{Body, HeaderMap, Method, Response, StatusCode};
+
+use pairing::PairError;
+use {error_page_html, Bod, RendezvousPayload, TYPE_HTML, TYPE_TEXT};
+
+pub static TYPE_JSON: &str = "application/json";
+
+pub fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub enum ApiError {
+    // arguments
+    UnknownArgument(&'static [&'static str]),
+    MissingArgument(&'static str),
+    // the argument, and what it should be
+    InvalidArgument(&'static str, &'static str),
+    ConflictingArguments,
+    InvalidFile(&'static str),
+    DuplicateFile,
+    TooManyFiles,
+    NameLooksGenerated,
+    LengthTooLarge,
+    LengthTooLargeToCache,
+
+    // ids
+    UnknownId,
+    BadSecret,
+    SecretRequired,
+    NamesDisabled,
+    NameTokenRequired,
+    NameTaken,
+    IdsExhausted,
+
+    // uploads
+    LengthRequired,
+    UnsupportedEncoding,
+    WrongLength,
+    EncodingMismatch,
+    UnknownTransfer,
+    DownloaderGone,
+    BundleUploadOnly,
+    ExpectedWebSocket,
+
+    // downloads
+    NotABundle,
+    UnknownFile,
+    TooLargeForZip,
+    RangeNotSatisfiable(u64),
+    NoUploader,
+    DownloaderTooSlow,
+
+    // pairing
+    UnknownCode,
+    CodeTaken,
+    BadToken,
+    MailboxFull,
+    MessageTooLong,
+    CodesExhausted,
+
+    // everything else
+    NotFound,
+    MethodNotAllowed(Vec<Method>),
+    // seconds until it's worth trying again
+    ShuttingDown(u64),
+    NodeUnavailable,
+    Internal,
+}
+
+use self::ApiError::*;
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            UnknownArgument(_)
+            | MissingArgument(_)
+            | InvalidArgument(..)
+            | ConflictingArguments
+            | InvalidFile(_)
+            | DuplicateFile
+            | TooManyFiles
+            | NameLooksGenerated
+            | LengthTooLarge
+            | LengthTooLargeToCache
+            | WrongLength
+            | EncodingMismatch
+            | BundleUploadOnly
+            | ExpectedWebSocket
+            | NotABundle
+            | TooLargeForZip => StatusCode::BAD_REQUEST,
+            SecretRequired | NameTokenRequired => StatusCode::UNAUTHORIZED,
+            BadSecret | NamesDisabled | BadToken => StatusCode::FORBIDDEN,
+            UnknownId | UnknownTransfer | UnknownFile | UnknownCode | NotFound => {
+                StatusCode::NOT_FOUND
+            }
+            MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            NameTaken | CodeTaken => StatusCode::CONFLICT,
+            DownloaderGone => StatusCode::GONE,
+            LengthRequired => StatusCode::LENGTH_REQUIRED,
+            MessageTooLong => StatusCode::PAYLOAD_TOO_LARGE,
+            UnsupportedEncoding => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            RangeNotSatisfiable(_) => StatusCode::RANGE_NOT_SATISFIABLE,
+            MailboxFull => StatusCode::TOO_MANY_REQUESTS,
+            Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            NodeUnavailable => StatusCode::BAD_GATEWAY,
+            IdsExhausted | NoUploader | CodesExhausted | ShuttingDown(_) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            DownloaderTooSlow => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            UnknownArgument(_) => "unknown_argument",
+            MissingArgument(_) => "missing_argument",
+            InvalidArgument(..) => "invalid_argument",
+            ConflictingArguments => "conflicting_arguments",
+            InvalidFile(_) => "invalid_file",
+            DuplicateFile => "duplicate_file",
+            TooManyFiles => "too_many_files",
+            NameLooksGenerated => "name_looks_generated",
+            LengthTooLarge => "length_too_large",
+            LengthTooLargeToCache => "length_too_large_to_cache",
+            UnknownId => "unknown_id",
+            BadSecret => "bad_secret",
+            SecretRequired => "secret_required",
+            NamesDisabled => "names_disabled",
+            NameTokenRequired => "name_token_required",
+            NameTaken => "name_taken",
+            IdsExhausted => "ids_exhausted",
+            LengthRequired => "length_required",
+            UnsupportedEncoding => "unsupported_encoding",
+            WrongLength => "wrong_length",
+            EncodingMismatch => "encoding_mismatch",
+            UnknownTransfer => "unknown_transfer",
+            DownloaderGone => "downloader_gone",
+            BundleUploadOnly => "bundle_upload_only",
+            ExpectedWebSocket => "expected_websocket",
+            NotABundle => "not_a_bundle",
+            UnknownFile => "unknown_file",
+            TooLargeForZip => "too_large_for_zip",
+            RangeNotSatisfiable(_) => "range_not_satisfiable",
+            NoUploader => "no_uploader",
+            DownloaderTooSlow => "downloader_too_slow",
+            UnknownCode => "unknown_code",
+            CodeTaken => "code_taken",
+            BadToken => "bad_token",
+            MailboxFull => "mailbox_full",
+            MessageTooLong => "message_too_long",
+            CodesExhausted => "codes_exhausted",
+            NotFound => "not_found",
+            MethodNotAllowed(_) => "method_not_allowed",
+            ShuttingDown(_) => "shutting_down",
+            NodeUnavailable => "node_unavailable",
+            Internal => "internal_error",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            UnknownArgument(supported) => {
+                let quoted: Vec<String> = supported.iter().map(|a| format!("\"{}\"", a)).collect();
+                match quoted.split_last() {
+                    Some((only, [])) => format!("The only supported argument is {}", only),
+                    Some((last, rest)) => {
+                        format!("Supported arguments are {} and {}", rest.join(", "), last)
+                    }
+                    None => "No arguments are supported".to_owned(),
+                }
+            }
+            MissingArgument(argument) => format!("Expected argument \"{}\"", argument),
+            InvalidArgument(argument, expected) => {
+                format!("\"{}\" should be {}", argument, expected)
+            }
+            ConflictingArguments => {
+                "\"length\", \"cache\" and \"encoding\" can't be used with \"file\"".to_owned()
+            }
+            InvalidFile(message) => (*message).to_owned(),
+            DuplicateFile => "File names should be unique".to_owned(),
+            TooManyFiles => "Too many files".to_owned(),
+            NameLooksGenerated => "\"name\" could be mistaken for a generated id".to_owned(),
+            LengthTooLarge => "Content is too long".to_owned(),
+            LengthTooLargeToCache => "Content is too long to cache".to_owned(),
+            UnknownId => "Unknown id".to_owned(),
+            BadSecret => "Bad secret".to_owned(),
+            SecretRequired => "The secret is needed as an Authorization: Bearer header".to_owned(),
+            NamesDisabled => "Choosing ids is not allowed".to_owned(),
+            NameTokenRequired => "Choosing ids requires a token".to_owned(),
+            NameTaken => "Name is taken".to_owned(),
+            IdsExhausted => "No free ids, try again later".to_owned(),
+            LengthRequired => "Content-Length must be specified".to_owned(),
+            UnsupportedEncoding => "Supported content encodings are gzip and br".to_owned(),
+            WrongLength => "Wrong length".to_owned(),
+            EncodingMismatch => "Content-Encoding doesn't match the id's encoding".to_owned(),
+            UnknownTransfer => "Unknown transfer".to_owned(),
+            DownloaderGone => "Downloader went away".to_owned(),
+            BundleUploadOnly => {
+                "Files of a bundle have to be uploaded with /1/file/upload".to_owned()
+            }
+            ExpectedWebSocket => "Expected a WebSocket upgrade".to_owned(),
+            NotABundle => "Not a bundle".to_owned(),
+            UnknownFile => "Unknown file".to_owned(),
+            TooLargeForZip => "Bundle is too large for a zip archive, use format=tar".to_owned(),
+            RangeNotSatisfiable(_) => "Range not satisfiable".to_owned(),
+            NoUploader => "No uploader currently available".to_owned(),
+            DownloaderTooSlow => "Downloader too slow, transfer aborted".to_owned(),
+            UnknownCode => "Unknown code".to_owned(),
+            CodeTaken => "Someone else already joined".to_owned(),
+            BadToken => "Bad token".to_owned(),
+            MailboxFull => "Too many messages waiting".to_owned(),
+            MessageTooLong => "Message is too long".to_owned(),
+            CodesExhausted => "No free codes, try again later".to_owned(),
+            NotFound => "There is nothing at this address".to_owned(),
+            MethodNotAllowed(_) => "Method not allowed".to_owned(),
+            ShuttingDown(_) => "Server is shutting down".to_owned(),
+            NodeUnavailable => "Owning node unavailable".to_owned(),
+            Internal => "Internal error".to_owned(),
+        }
+    }
+
+    fn headers(&self, headers: &mut HeaderMap) {
+        match self {
+            SecretRequired | NameTokenRequired => {
+                headers.insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            }
+            RangeNotSatisfiable(total) => {
+                headers.insert(
+                    header::CONTENT_RANGE,
+                    HeaderValue::from_str(&format!("bytes */{}", total)).unwrap(),
+                );
+            }
+            MethodNotAllowed(allowed) => {
+                let allowed: Vec<&str> = allowed.iter().map(Method::as_str).collect();
+                headers.insert(
+                    header::ALLOW,
+                    HeaderValue::from_str(&allowed.join(", ")).unwrap(),
+                );
+            }
+            ShuttingDown(retry_after) => {
+                headers.insert(header::RETRY_AFTER, HeaderValue::from(*retry_after));
+            }
+            _ => {}
+        }
+    }
+
+    pub fn response(self) -> Response<RendezvousPayload> {
+        let mut response = Response::new(Bod(Body::from(self.message())));
+        *response.status_mut() = self.status();
+        response
+            .headers_mut()
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static(TYPE_TEXT));
+        // rendered according to Accept
+        response
+            .headers_mut()
+            .insert(header::VARY, HeaderValue::from_static("accept"));
+        self.headers(response.headers_mut());
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+impl From<PairError> for ApiError {
+    fn from(error: PairError) -> ApiError {
+        match error {
+            PairError::Unknown => UnknownCode,
+            PairError::Taken => CodeTaken,
+            PairError::Forbidden => BadToken,
+            PairError::Full => MailboxFull,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub enum Flavor {
+    Text,
+    Html,
+    Json,
+}
+
+impl Flavor {
+    // Whichever of JSON, HTML or plain text the Accept header rates highest, the first listed
+    // winning a tie. Plain text without one, as for curl.
+    pub fn accepted(headers: &HeaderMap) -> Flavor {
+        let accept = match headers.get(header::ACCEPT).and_then(|h| h.to_str().ok()) {
+            Some(accept) => accept,
+            None => return Flavor::Text,
+        };
+
+        let mut best = (Flavor::Text, 0.0);
+        for range in accept.split(',') {
+            let mut parts = range.split(';');
+            let flavor = match parts.next().unwrap().trim() {
+                "application/json" => Flavor::Json,
+                "text/html" => Flavor::Html,
+                "text/plain" | "text/*" | "*/*" => Flavor::Text,
+                _ => continue,
+            };
+            let quality = parts
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .filter_map(|q| q.parse::<f32>().ok())
+                .next()
+                .unwrap_or(1.0);
+            if quality > best.1 {
+                best = (flavor, quality);
+            }
+        }
+        best.0
+    }
+}
+
+// The error a response was made from, if any, written out the way the client prefers.
+pub fn render(
+    response: Response<RendezvousPayload>,
+    flavor: Flavor,
+) -> Response<RendezvousPayload> {
+    if flavor == Flavor::Text || response.extensions().get::<ApiError>().is_none() {
+        return response;
+    }
+
+    let (mut parts, _) = response.into_parts();
+    let error = parts.extensions.remove::<ApiError>().unwrap();
+    let (content_type, body) = match flavor {
+        Flavor::Json => (
+            TYPE_JSON,
+            format!(
+                r#"{{"error":"{}","message":{}}}"#,
+                error.code(),
+                json_string(&error.message())
+            ),
+        ),
+        _ => (TYPE_HTML, error_page_html(error.status(), &error.message())),
+    };
+    parts
+        .headers
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Bod(Body::from(body)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flavor(accept: &'static str) -> Flavor {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_static(accept));
+        Flavor::accepted(&headers)
+    }
+
+    #[test]
+    fn plain_text_without_a_preference() {
+        assert!(Flavor::accepted(&HeaderMap::new()) == Flavor::Text);
+        assert!(flavor("*/*") == Flavor::Text);
+        assert!(flavor("image/png") == Flavor::Text);
+    }
+
+    #[test]
+    fn browsers_get_html_and_scripts_json() {
+        assert!(
+            flavor("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8")
+                == Flavor::Html
+        );
+        assert!(flavor("application/json") == Flavor::Json);
+    }
+
+    #[test]
+    fn the_highest_quality_wins_and_then_the_first_listed() {
+        assert!(flavor("text/html;q=0.5, application/json;q=0.9") == Flavor::Json);
+        assert!(flavor("application/json, text/html") == Flavor::Json);
+        assert!(flavor("text/html, application/json") == Flavor::Html);
+        assert!(flavor("text/html;q=0, */*;q=0.1") == Flavor::Text);
+    }
+}
@@ -0,0 +1,40 @@
+use futures::sync::mpsc;
+use futures::Stream;
+use hyper::{Body, Chunk};
+use std::io;
+use std::sync::{Arc, Mutex};
+
+// Fans out Server-Sent Events about a paste to whoever is watching it. Clones share subscribers,
+// so forwarders can keep reporting on a transfer after leaving the paste.
+#[derive(Clone, Default)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub struct Events {
+    subscribers: Arc<Mutex<Vec<mpsc::UnboundedSender<Chunk>>>>,
+}
+
+impl Events {
+    pub fn subscribe(&self) -> Body {
+        let (sender, receiver) = mpsc::unbounded();
+        // tell EventSource to hold off a bit when reconnecting after we close the stream
+        let _ = sender.unbounded_send(Chunk::from("retry: 5000\n\n"));
+        self.subscribers.lock().unwrap().push(sender);
+
+        Body::wrap_stream(receiver.map_err(|()| io::Error::other("events dropped")))
+    }
+
+    // data should be a single line of JSON
+    pub fn send(&self, event: &str, data: &str) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+
+        let message = format!("event: {}\ndata: {}\n\n", event, data);
+        subscribers.retain(|s| s.unbounded_send(Chunk::from(message.clone())).is_ok());
+    }
+
+    // End every subscriber's stream.
+    pub fn close(&self) {
+        self.subscribers.lock().unwrap().clear();
+    }
+}
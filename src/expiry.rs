@@ -0,0 +1,215 @@
+use futures::task::{self, AtomicTask};
+use futures::{Async, Future, Poll, Stream};
+use futures_timer::Delay;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+// Deadlines are never removed from the heap when a paste is refreshed; instead a new deadline is
+// pushed, and the old one is skipped when it comes up, as it no longer matches the entry's latest.
+// This keeps scheduling at O(log n) without having to find old entries. Once stale deadlines
+// outnumber live ones the heap is rebuilt, so it doesn't grow with the number of refreshes.
+//
+// Whoever consumes an expired entry still has to check that what it names is actually due, as it
+// may have been retired or replaced since.
+pub struct ExpiryQueue<T: Ord + Clone> {
+    deadlines: Mutex<Deadlines<T>>,
+    task: AtomicTask,
+}
+
+struct Deadlines<T: Ord> {
+    heap: BinaryHeap<Reverse<(Instant, T)>>,
+    // The latest deadline of each entry.
+    latest: BTreeMap<T, Instant>,
+}
+
+// Extra room before a rebuild, so small queues aren't rebuilt all the time.
+const STALE_SLACK: usize = 64;
+
+impl<T: Ord + Clone> ExpiryQueue<T> {
+    pub fn new() -> ExpiryQueue<T> {
+        ExpiryQueue {
+            deadlines: Mutex::new(Deadlines {
+                heap: BinaryHeap::new(),
+                latest: BTreeMap::new(),
+            }),
+            task: AtomicTask::new(),
+        }
+    }
+
+    pub fn schedule(&self, entry: T, at: Instant) {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        let earliest = match deadlines.heap.peek() {
+            Some(Reverse((head, _))) => at < *head,
+            None => true,
+        };
+        deadlines.latest.insert(entry.clone(), at);
+        deadlines.heap.push(Reverse((at, entry)));
+
+        if deadlines.heap.len() > 2 * deadlines.latest.len() + STALE_SLACK {
+            let heap = deadlines
+                .latest
+                .iter()
+                .map(|(entry, at)| Reverse((*at, entry.clone())))
+                .collect();
+            deadlines.heap = heap;
+        }
+        drop(deadlines);
+
+        if earliest {
+            // the timer is sleeping until a later deadline (or not at all), wake it to re-arm
+            self.task.notify();
+        }
+    }
+
+    pub fn expired(queue: &Arc<ExpiryQueue<T>>) -> Expired<T> {
+        Expired {
+            queue: queue.clone(),
+            delay: None,
+        }
+    }
+}
+
+// Yields entries whose deadline has passed, sleeping until the earliest one in between.
+pub struct Expired<T: Ord + Clone> {
+    queue: Arc<ExpiryQueue<T>>,
+    delay: Option<Delay>,
+}
+
+impl<T: Ord + Clone> Stream for Expired<T> {
+    type Item = T;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<T>, ()> {
+        loop {
+            // register before looking at the heap so a concurrent schedule() can't be missed
+            self.queue.task.register();
+
+            let next = {
+                let mut deadlines = self.queue.deadlines.lock().unwrap();
+                let now = Instant::now();
+                match deadlines.heap.peek() {
+                    Some(Reverse((at, _))) if *at <= now => {
+                        let Reverse((at, entry)) = deadlines.heap.pop().unwrap();
+                        if deadlines.latest.get(&entry) != Some(&at) {
+                            // superseded by a later deadline
+                            continue;
+                        }
+                        deadlines.latest.remove(&entry);
+                        return Ok(Async::Ready(Some(entry)));
+                    }
+                    Some(Reverse((at, _))) => Some(*at),
+                    None => None,
+                }
+            };
+
+            match next {
+                Some(at) => {
+                    match self.delay {
+                        Some(ref mut delay) => delay.reset_at(at),
+                        None => self.delay = Some(Delay::new_at(at)),
+                    }
+                    match self.delay.as_mut().unwrap().poll() {
+                        Ok(Async::Ready(())) => continue,
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Err(e) => {
+                            // Try again with a new timer, rather than stop expiring anything.
+                            eprintln!("expiry timer error: {}", e);
+                            self.delay = None;
+                            task::current().notify();
+                            return Ok(Async::NotReady);
+                        }
+                    }
+                }
+                None => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::{self, Notify, NotifyHandle};
+    use std::time::Duration;
+
+    struct Ignore;
+
+    impl Notify for Ignore {
+        fn notify(&self, _: usize) {}
+    }
+
+    // Whatever has expired by now, in the order it comes out.
+    fn due(queue: &Arc<ExpiryQueue<u32>>) -> Vec<u32> {
+        let mut expired = executor::spawn(ExpiryQueue::expired(queue));
+        let notify = NotifyHandle::from(Arc::new(Ignore));
+        let mut due = Vec::new();
+        while let Ok(Async::Ready(Some(entry))) = expired.poll_stream_notify(&notify, 0) {
+            due.push(entry);
+        }
+        due
+    }
+
+    fn ago(secs: u64) -> Instant {
+        Instant::now() - Duration::from_secs(secs)
+    }
+
+    fn from_now(secs: u64) -> Instant {
+        Instant::now() + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn entries_come_out_in_deadline_order() {
+        let queue = Arc::new(ExpiryQueue::new());
+        queue.schedule(1, ago(1));
+        queue.schedule(2, ago(3));
+        queue.schedule(3, from_now(60));
+        queue.schedule(4, ago(2));
+        assert_eq!(due(&queue), vec![2, 4, 1]);
+        assert_eq!(due(&queue), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn a_later_deadline_supersedes_an_earlier_one() {
+        let queue = Arc::new(ExpiryQueue::new());
+        queue.schedule(1, ago(1));
+        queue.schedule(1, from_now(60));
+        assert_eq!(due(&queue), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn an_earlier_deadline_comes_out_once() {
+        let queue = Arc::new(ExpiryQueue::new());
+        queue.schedule(1, from_now(60));
+        queue.schedule(1, ago(1));
+        assert_eq!(due(&queue), vec![1]);
+
+        // the stale deadline is skipped for good, even once it's due
+        queue.schedule(1, ago(2));
+        assert_eq!(due(&queue), vec![1]);
+    }
+
+    #[test]
+    fn stale_deadlines_are_dropped_on_rebuild() {
+        let queue = Arc::new(ExpiryQueue::new());
+        let last = from_now(10 * STALE_SLACK as u64);
+        for i in 0..10 * STALE_SLACK as u64 {
+            queue.schedule(1, from_now(i));
+            queue.schedule(2, from_now(i));
+        }
+        queue.schedule(1, last);
+        queue.schedule(2, last);
+        let heap = queue.deadlines.lock().unwrap().heap.len();
+        assert!(heap <= 4 + STALE_SLACK, "{} deadlines kept", heap);
+
+        // the latest deadlines survive it
+        queue.schedule(3, ago(1));
+        assert_eq!(due(&queue), vec![3]);
+        let latest = queue.deadlines.lock().unwrap().latest.clone();
+        assert_eq!(
+            latest.into_iter().collect::<Vec<_>>(),
+            vec![(1, last), (2, last)]
+        );
+    }
+}
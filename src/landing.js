@@ -0,0 +1,68 @@
+'use strict';
+
+var previewButton = document.getElementById('preview-button');
+var preview = document.getElementById('preview');
+
+var KEYWORDS = [
+  'as', 'async', 'await', 'break', 'case', 'catch', 'class', 'const', 'continue', 'def', 'default',
+  'do', 'elif', 'else', 'enum', 'except', 'export', 'extends', 'false', 'final', 'finally', 'fn',
+  'for', 'from', 'func', 'function', 'if', 'impl', 'import', 'in', 'interface', 'let', 'match',
+  'mod', 'mut', 'new', 'nil', 'none', 'None', 'null', 'package', 'private', 'pub', 'public',
+  'return', 'self', 'static', 'struct', 'switch', 'this', 'throw', 'trait', 'true', 'True',
+  'False', 'try', 'type', 'use', 'var', 'void', 'while', 'with', 'yield'
+];
+
+// Comments, strings, numbers and common keywords across most languages. Anything smarter would
+// need to know which language it's looking at.
+var TOKENS = new RegExp([
+  '(\\/\\/[^\\n]*|#[^\\n]*|\\/\\*[\\s\\S]*?\\*\\/)',
+  '("(?:[^"\\\\\\n]|\\\\.)*"|\'(?:[^\'\\\\\\n]|\\\\.)*\'|`(?:[^`\\\\]|\\\\.)*`)',
+  '(\\b\\d+(?:\\.\\d+)?\\b)',
+  '(\\b(?:' + KEYWORDS.join('|') + ')\\b)'
+].join('|'), 'g');
+
+function escapeHtml(text) {
+  return text.replace(/&/g, '&amp;').replace(/</g, '&lt;').replace(/>/g, '&gt;');
+}
+
+function highlight(text) {
+  var html = '';
+  var last = 0;
+  var match;
+  TOKENS.lastIndex = 0;
+  while ((match = TOKENS.exec(text)) !== null) {
+    var kind = match[1] ? 'comment' : match[2] ? 'string' : match[3] ? 'number' : 'keyword';
+    html += escapeHtml(text.slice(last, match.index));
+    html += '<span class="' + kind + '">' + escapeHtml(match[0]) + '</span>';
+    last = TOKENS.lastIndex;
+  }
+  return html + escapeHtml(text.slice(last));
+}
+
+if (previewButton) {
+  previewButton.onclick = function () {
+    previewButton.disabled = true;
+    previewButton.innerText = 'Loading...';
+
+    var xhr = new XMLHttpRequest();
+    xhr.open('GET', previewButton.getAttribute('data-src'));
+    xhr.onload = function () {
+      preview.hidden = false;
+      if (xhr.status == 200) {
+        preview.innerHTML = highlight(xhr.responseText);
+        previewButton.hidden = true;
+      } else {
+        preview.innerText = 'Could not load the preview (HTTP ' + xhr.status + ')';
+        previewButton.disabled = false;
+        previewButton.innerText = 'Try again';
+      }
+    };
+    xhr.onerror = function () {
+      preview.hidden = false;
+      preview.innerText = 'Could not load the preview';
+      previewButton.disabled = false;
+      previewButton.innerText = 'Try again';
+    };
+    xhr.send();
+  };
+}
@@ -1,3 +1,8 @@
+extern crate base64;
+extern crate brotli;
+extern crate bytes;
+extern crate crc32fast;
+extern crate flate2;
 extern crate futures;
 extern crate futures_timer;
 extern crate hyper;
@@ -8,38 +13,108 @@ extern crate lazy_static;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
+extern crate sha1;
+extern crate sha2;
+extern crate tokio;
+extern crate tokio_rustls;
+extern crate tokio_signal;
+extern crate tokio_threadpool;
+extern crate tokio_tungstenite;
 extern crate toml;
 
+mod api_v2;
+mod assets;
+mod broadcast;
+mod bundle;
+mod cache;
+mod cluster;
+mod config;
+mod cors;
+mod encoding;
+mod error;
+mod events;
+mod expiry;
+mod pairing;
+mod router;
+mod socket;
+mod state;
+#[cfg(test)]
+mod testing;
+mod tls;
+mod words;
+
+use assets::{Asset, Assets};
+use bundle::Format;
+use bytes::Bytes;
+use cache::Cache;
+use cluster::Cluster;
+use encoding::{Coding, Encoding};
+use error::{ApiError, Flavor};
+use events::Events;
+use expiry::ExpiryQueue;
 use futures::{future, sync};
 use futures::{Async, Poll};
-use futures_timer::Delay;
+use futures_timer::{Delay, Interval};
 use hyper::body::Payload;
 use hyper::header::{self, HeaderValue};
 use hyper::rt::{Future, Stream};
+use hyper::server::conn::AddrIncoming;
 use hyper::service::service_fn;
 use hyper::{Body, Chunk, HeaderMap, Method, Request, Response, Server, StatusCode, Uri};
+use pairing::{Pairings, Received};
 use rand::prelude::*;
-use std::collections::hash_map::Entry;
+use sha2::{Digest, Sha256};
+use socket::SocketRequest;
+use std::collections::hash_map::{Entry, RandomState};
 use std::collections::{HashMap, VecDeque};
 use std::env;
-use std::ffi::OsString;
-use std::fs::File;
-use std::io::Read;
+use std::error::Error;
+use std::hash::BuildHasher;
 use std::iter;
+use std::path::PathBuf;
 use std::process;
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::runtime::Runtime;
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct Config {
     #[serde(default = "default_bind")]
     bind: String,
 
+    #[serde(default = "default_http2")]
+    http2: bool,
+
+    #[serde(default)]
+    tls_cert_file: Option<String>,
+
+    #[serde(default)]
+    tls_key_file: Option<String>,
+
+    #[serde(default)]
+    base_path: String,
+
+    #[serde(default)]
+    public_url: Option<String>,
+
+    #[serde(default)]
+    assets_dir: Option<String>,
+
+    #[serde(default)]
+    assets_reload: bool,
+
+    #[serde(default = "default_assets_max_age_secs")]
+    assets_max_age_secs: u64,
+
     #[serde(default = "default_timeout_secs")]
     timeout_secs: u64,
 
-    #[serde(default = "default_timeout_scan_interval_secs")]
-    timeout_scan_interval_secs: u64,
+    // No longer used, as pastes expire at their own deadlines instead of being scanned for. Still
+    // accepted so that older configuration files load.
+    #[serde(default, skip_serializing)]
+    timeout_scan_interval_secs: Option<u64>,
 
     #[serde(default = "default_download_retry_ms")]
     download_retry_ms: u64,
@@ -50,19 +125,82 @@ struct Config {
     #[serde(default = "default_token_length")]
     token_length: usize,
 
+    #[serde(default)]
+    id_scheme: IdScheme,
+
+    #[serde(default = "default_id_words")]
+    id_words: usize,
+
+    #[serde(default)]
+    vanity_ids: bool,
+
+    #[serde(default)]
+    vanity_id_tokens: Vec<String>,
+
+    #[serde(default)]
+    cors_allowed_origins: Vec<String>,
+
     #[serde(default = "default_max_content_length")]
     max_content_length: u64,
+
+    #[serde(default = "default_wait_timeout_secs")]
+    wait_timeout_secs: u64,
+
+    #[serde(default = "default_cache_size_bytes")]
+    cache_size_bytes: u64,
+
+    #[serde(default)]
+    compress_downloads: bool,
+
+    #[serde(default)]
+    broadcast_window_ms: u64,
+
+    #[serde(default = "default_broadcast_buffer_bytes")]
+    broadcast_buffer_bytes: u64,
+
+    #[serde(default = "default_broadcast_lag_timeout_ms")]
+    broadcast_lag_timeout_ms: u64,
+
+    #[serde(default = "default_transfer_idle_timeout_secs")]
+    transfer_idle_timeout_secs: u64,
+
+    #[serde(default = "default_transfer_min_bytes_per_sec")]
+    transfer_min_bytes_per_sec: u64,
+
+    #[serde(default = "default_expiry_warning_secs")]
+    expiry_warning_secs: u64,
+
+    #[serde(default = "default_pairing_timeout_secs")]
+    pairing_timeout_secs: u64,
+
+    #[serde(default = "default_shutdown_drain_secs")]
+    shutdown_drain_secs: u64,
+
+    #[serde(default)]
+    state_file: Option<String>,
+
+    #[serde(default = "default_state_save_interval_secs")]
+    state_save_interval_secs: u64,
+
+    #[serde(default)]
+    node_id: Option<String>,
+
+    #[serde(default)]
+    nodes: HashMap<String, String>,
+
+    #[serde(default)]
+    cluster_redirect: bool,
 }
 
 fn default_bind() -> String {
     String::from("127.0.0.1:3000")
 }
+fn default_http2() -> bool {
+    true
+}
 fn default_timeout_secs() -> u64 {
     60 * 60
 }
-fn default_timeout_scan_interval_secs() -> u64 {
-    60
-}
 fn default_download_retry_ms() -> u64 {
     200
 }
@@ -72,49 +210,128 @@ fn default_download_max_retries() -> u64 {
 fn default_token_length() -> usize {
     10
 }
+fn default_id_words() -> usize {
+    4
+}
 fn default_max_content_length() -> u64 {
     1024 * 1024
 }
+fn default_wait_timeout_secs() -> u64 {
+    30
+}
+fn default_cache_size_bytes() -> u64 {
+    16 * 1024 * 1024
+}
+fn default_assets_max_age_secs() -> u64 {
+    3600
+}
+fn default_broadcast_buffer_bytes() -> u64 {
+    1024 * 1024
+}
+fn default_broadcast_lag_timeout_ms() -> u64 {
+    5000
+}
+fn default_transfer_idle_timeout_secs() -> u64 {
+    60
+}
+fn default_transfer_min_bytes_per_sec() -> u64 {
+    1024
+}
+fn default_expiry_warning_secs() -> u64 {
+    5 * 60
+}
+fn default_pairing_timeout_secs() -> u64 {
+    600
+}
+fn default_shutdown_drain_secs() -> u64 {
+    30
+}
+fn default_state_save_interval_secs() -> u64 {
+    5
+}
 
-lazy_static! {
-    static ref CONFIG: Arc<Config> = Arc::new({
-        let mut config_string = String::new();
-        let args: Vec<OsString> = env::args_os().collect();
-        if args.len() >= 2 {
-            let mut config_file = match File::open(&args[1]) {
-                Err(e) => {
-                    eprintln!("Error opening configuration file: {}", e);
-                    process::exit(1);
-                }
-                Ok(file) => file,
-            };
+// How ids are generated. Secrets are always Base58.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+enum IdScheme {
+    #[default]
+    Base58,
+    Numeric,
+    Words,
+}
 
-            match config_file.read_to_string(&mut config_string) {
+// The key secrets are hashed with, from next to the state file if there is one.
+struct SecretKey {
+    key: [u8; 32],
+    // Whether it was just made up, so hashes saved with an earlier one can't be checked.
+    fresh: bool,
+}
+
+lazy_static! {
+    static ref SECRET_KEY: SecretKey = match config().state_file {
+        Some(ref path) => {
+            let path = format!("{}.key", path);
+            match state::load_key(&path) {
+                Ok((key, fresh)) => SecretKey { key, fresh },
                 Err(e) => {
-                    eprintln!("Error reading configuration file: {}", e);
+                    eprintln!("Error reading key file {}: {}", path, e);
                     process::exit(1);
                 }
-                _ => {}
-            };
-        }
-        match toml::from_str(&config_string) {
-            Err(e) => {
-                eprintln!("Error parsing configuration file: {}", e);
-                process::exit(1);
             }
-            Ok(c) => c,
         }
-    });
+        None => {
+            let mut key = [0; 32];
+            thread_rng().fill(&mut key);
+            SecretKey { key, fresh: true }
+        }
+    };
+}
+
+lazy_static! {
+    static ref ASSETS: Assets = {
+        let config = config();
+        Assets::new(
+            config.assets_dir.as_ref().map(PathBuf::from),
+            config.assets_reload,
+        )
+    };
+}
+
+lazy_static! {
+    // Swapped for a new one when the configuration is reloaded on SIGHUP.
+    static ref CONFIG: RwLock<Arc<Config>> =
+        RwLock::new(Arc::new(config::load(&config::ARGS).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        })));
+}
+
+// The configuration in force. Requests hold on to it for no longer than they take, so reloads
+// apply from the next one.
+fn config() -> Arc<Config> {
+    CONFIG.read().unwrap().clone()
+}
+
+// Swaps in the configuration as it is now. Pastes already made keep the timeout and size limit
+// they were made with.
+fn reload_config() {
+    match config::reload(&config()) {
+        Ok((new, changes)) => {
+            *CONFIG.write().unwrap() = Arc::new(new);
+            config::log_changes(&changes);
+        }
+        Err(e) => eprintln!("Keeping the old configuration: {}", e),
+    }
 }
 
-static TYPE_TEXT: &'static str = "text/plain; charset=utf-8";
-static TYPE_HTML: &'static str = "text/html; charset=utf-8";
+static TYPE_TEXT: &str = "text/plain; charset=utf-8";
+static TYPE_HTML: &str = "text/html; charset=utf-8";
+static TYPE_EVENTS: &str = "text/event-stream";
 
-static FAVICON: &'static [u8] = include_bytes!("favicon.ico");
-static UPLOADER_HTML: &'static str = include_str!("uploader.html");
-static CLIENT_JS: &'static str = include_str!("client.js");
+// Tries at a free id before giving up.
+const MAX_ID_ATTEMPTS: usize = 100;
 
-static BASE58: &'static [char] = &[
+static BASE58: &[char] = &[
     '1', '2', '3', '4', '5', '6', '7', '8', '9', 'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'J', 'K',
     'L', 'M', 'N', 'P', 'Q', 'R', 'S', 'T', 'U', 'V', 'W', 'X', 'Y', 'Z', 'a', 'b', 'c', 'd', 'e',
     'f', 'g', 'h', 'i', 'j', 'k', 'm', 'n', 'o', 'p', 'q', 'r', 's', 't', 'u', 'v', 'w', 'x', 'y',
@@ -124,60 +341,278 @@ static BASE58: &'static [char] = &[
 #[cfg_attr(debug_assertions, derive(Debug))]
 enum RendezvousPayload {
     Bod(Body),
-    Fwd(Forwarder),
+    // Shared with the watchdog, which can take the uploader away from a downloader that stalls.
+    Fwd(Arc<Mutex<Forwarder>>),
+    // A copy of a transfer being broadcast to several downloaders.
+    Tap(broadcast::Tap<RendezvousPayload>),
+    // Compressed or decompressed for the downloader.
+    Enc(Box<encoding::Encoded<RendezvousPayload>>),
+    // All the files of a bundle, one transfer after the other.
+    Bun(Box<bundle::Archive<RendezvousPayload>>),
 }
 
-use RendezvousPayload::{Bod, Fwd};
+use RendezvousPayload::{Bod, Bun, Enc, Fwd, Tap};
+
+// Unlike hyper::Error, this can also carry a failure to decompress.
+type PayloadError = Box<dyn Error + Send + Sync>;
 
 impl Payload for RendezvousPayload {
     type Data = Chunk;
-    type Error = hyper::Error;
+    type Error = PayloadError;
 
-    fn poll_data(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+    fn poll_data(&mut self) -> Poll<Option<Chunk>, PayloadError> {
         match self {
-            Fwd(f) => f.poll_data(),
-            Bod(b) => b.poll_data(),
+            Fwd(f) => f.lock().unwrap().poll_data().map_err(Into::into),
+            Tap(t) => t.poll_data(),
+            Enc(e) => e.poll_data(),
+            Bun(a) => a.poll_data(),
+            Bod(b) => b.poll_data().map_err(Into::into),
         }
     }
-    fn poll_trailers(&mut self) -> Poll<Option<HeaderMap>, hyper::Error> {
+    fn poll_trailers(&mut self) -> Poll<Option<HeaderMap>, PayloadError> {
         match self {
-            Fwd(f) => f.poll_trailers(),
-            Bod(b) => b.poll_trailers(),
+            Fwd(f) => f.lock().unwrap().poll_trailers().map_err(Into::into),
+            Tap(t) => t.poll_trailers(),
+            Enc(e) => e.poll_trailers(),
+            Bun(a) => a.poll_trailers(),
+            Bod(b) => b.poll_trailers().map_err(Into::into),
         }
     }
     fn is_end_stream(&self) -> bool {
         match self {
-            Fwd(f) => f.is_end_stream(),
+            Fwd(f) => f.lock().unwrap().is_end_stream(),
+            Tap(t) => t.is_end_stream(),
+            Enc(e) => e.is_end_stream(),
+            Bun(a) => a.is_end_stream(),
             Bod(b) => b.is_end_stream(),
         }
     }
     fn content_length(&self) -> Option<u64> {
         match self {
-            Fwd(f) => f.content_length(),
+            Fwd(f) => f.lock().unwrap().content_length(),
+            Tap(t) => t.content_length(),
+            Enc(e) => e.content_length(),
+            Bun(a) => a.content_length(),
             Bod(b) => b.content_length(),
         }
     }
 }
 
+#[cfg_attr(debug_assertions, derive(Debug))]
+enum Uploader {
+    // A POST to /1/file/upload, answered once its body has been forwarded.
+    Post(Body, sync::oneshot::Sender<Response<RendezvousPayload>>),
+    // A transfer requested over the uploader's WebSocket, fed by the socket session, which ends it
+    // with an error if the socket closes before all of it was sent.
+    Socket(Body),
+}
+
+impl Uploader {
+    fn poll_chunk(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        match self {
+            Uploader::Post(body, _) => body.poll(),
+            Uploader::Socket(chunks) => chunks.poll(),
+        }
+    }
+
+    fn is_canceled(&self) -> bool {
+        match self {
+            Uploader::Post(_, complete) => complete.is_canceled(),
+            Uploader::Socket(_) => false,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        match self {
+            Uploader::Post(body, _) => body.is_end_stream(),
+            Uploader::Socket(_) => false,
+        }
+    }
+}
+
 #[cfg_attr(debug_assertions, derive(Debug))]
 struct Forwarder {
     length: u64,
     bytes_sent: u64,
-    uploader: Option<(Body, sync::oneshot::Sender<Response<RendezvousPayload>>)>,
+    uploader: Option<Uploader>,
+    // Where to report how the transfer went, set once a downloader has picked it up.
+    events: Option<Events>,
+    // When a downloader picked it up, and when anything was last forwarded to them.
+    started: Instant,
+    last_progress: Instant,
+    // Since when the downloader has been waiting on the uploader rather than the other way round,
+    // and how long it waited before, which isn't held against the downloader.
+    awaiting_upload: Option<Instant>,
+    upload_wait: Duration,
+    // Content kept for the cache as it goes by, for pastes that opted in.
+    capture: Option<Capture>,
+}
+
+#[cfg_attr(debug_assertions, derive(Debug))]
+struct Capture {
+    cache: Arc<Cache>,
+    id: String,
+    content: Vec<u8>,
 }
 
 impl Forwarder {
-    fn handle_last_chunk(&mut self) {
-        let (_, complete) = self.uploader.take().unwrap();
-        if let Err(_) = complete.send(
-            Response::builder()
-                .header(header::CONTENT_TYPE, TYPE_TEXT)
-                .body(Bod(Body::from("Sent!")))
-                .unwrap(),
-        ) {
-            // hit an error
-            // TODO what if we can't talk back to the uploader? Should this
-            // be considered an error for the downloader?
+    fn new(length: u64, uploader: Uploader) -> Forwarder {
+        Forwarder {
+            length,
+            bytes_sent: 0,
+            uploader: Some(uploader),
+            events: None,
+            started: Instant::now(),
+            last_progress: Instant::now(),
+            awaiting_upload: None,
+            upload_wait: Duration::from_secs(0),
+            capture: None,
+        }
+    }
+
+    // Hand the transfer to a downloader. With broadcasting on, a whole transfer is shared with
+    // downloaders arriving shortly after.
+    fn hand_out(
+        mut self,
+        id: &str,
+        paste: &mut Paste,
+        whole: bool,
+        in_flight: &InFlight,
+    ) -> RendezvousPayload {
+        self.events = Some(paste.events.clone());
+        if whole && paste.cache && !in_flight.cache.contains(id) {
+            self.capture = Some(Capture {
+                cache: in_flight.cache.clone(),
+                id: id.to_owned(),
+                content: Vec::with_capacity(self.length as usize),
+            });
+        }
+        let payload = self.watch(in_flight);
+
+        let config = config();
+        if whole && config.broadcast_window_ms > 0 {
+            let (joiner, tap) = broadcast::start(
+                payload,
+                Duration::from_millis(config.broadcast_window_ms),
+                config.broadcast_buffer_bytes,
+                Duration::from_millis(config.broadcast_lag_timeout_ms),
+            );
+            paste.broadcast = Some(joiner);
+            Tap(tap)
+        } else {
+            payload
+        }
+    }
+
+    // Put the transfer under the watchdog's eye.
+    fn watch(mut self, in_flight: &InFlight) -> RendezvousPayload {
+        self.started = Instant::now();
+        self.last_progress = self.started;
+        self.upload_wait = Duration::from_secs(0);
+
+        let forwarder = Arc::new(Mutex::new(self));
+        in_flight
+            .transfers
+            .lock()
+            .unwrap()
+            .push(Arc::downgrade(&forwarder));
+        Fwd(forwarder)
+    }
+
+    // Whether the downloader has gone too long without reading anything, or has been reading
+    // slower than the minimum throughput once the grace period is over.
+    fn is_stalled(&self, now: Instant) -> bool {
+        if self.uploader.is_none() || self.awaiting_upload.is_some() {
+            return false;
+        }
+        let grace = Duration::from_secs(config().transfer_idle_timeout_secs);
+        if now - self.last_progress >= grace {
+            return true;
+        }
+
+        let elapsed = self.downloading_time(now);
+        elapsed >= grace && self.bytes_sent < self.length && self.is_slow(now)
+    }
+
+    // How long the transfer has been going, less the time spent waiting on the uploader.
+    fn downloading_time(&self, now: Instant) -> Duration {
+        let waiting = self
+            .awaiting_upload
+            .map_or(Duration::from_secs(0), |t| now - t);
+        (now - self.started)
+            .checked_sub(self.upload_wait + waiting)
+            .unwrap_or_default()
+    }
+
+    fn is_slow(&self, now: Instant) -> bool {
+        let elapsed = self.downloading_time(now).as_secs_f64();
+        elapsed > 0.0
+            && (self.bytes_sent as f64 / elapsed) < config().transfer_min_bytes_per_sec as f64
+    }
+
+    // Give up on a stalled downloader and free the uploader. The downloader's response ends
+    // early the next time it is polled.
+    fn abort_stalled(&mut self) {
+        if let Some(events) = self.events.take() {
+            events.send(
+                "aborted",
+                &format!(r#"{{"bytes":{},"reason":"stalled"}}"#, self.bytes_sent),
+            );
+        }
+
+        // dropping a socket transfer's receiver tells the session to abort it
+        if let Some(Uploader::Post(_, complete)) = self.uploader.take() {
+            let _ = complete.send(ApiError::DownloaderTooSlow.response());
+        }
+    }
+
+    // Answer the uploader without having forwarded anything.
+    fn reject(mut self, response: Response<RendezvousPayload>) {
+        if let Some(Uploader::Post(_, complete)) = self.uploader.take() {
+            let _ = complete.send(response);
+        }
+    }
+
+    fn handle_last_chunk(&mut self, chunk: &Chunk) {
+        if let Some(mut capture) = self.capture.take() {
+            capture.content.extend_from_slice(chunk);
+            if capture.content.len() as u64 == self.length {
+                capture
+                    .cache
+                    .insert(capture.id, Bytes::from(capture.content));
+            }
+        }
+
+        if let Some(events) = self.events.take() {
+            events.send("completed", &format!(r#"{{"bytes":{}}}"#, self.bytes_sent));
+        }
+
+        // the socket session keeps track of its own transfers
+        if let Some(Uploader::Post(_, complete)) = self.uploader.take() {
+            if complete
+                .send(
+                    Response::builder()
+                        .header(header::CONTENT_TYPE, TYPE_TEXT)
+                        .body(Bod(Body::from("Sent!")))
+                        .unwrap(),
+                )
+                .is_err()
+            {
+                // hit an error
+                // TODO what if we can't talk back to the uploader? Should this
+                // be considered an error for the downloader?
+            }
+        }
+    }
+}
+
+impl Drop for Forwarder {
+    fn drop(&mut self) {
+        // the downloader went away, or the uploader did
+        if let Some(ref events) = self.events {
+            if self.uploader.is_some() {
+                events.send("aborted", &format!(r#"{{"bytes":{}}}"#, self.bytes_sent));
+            }
         }
     }
 }
@@ -189,16 +624,29 @@ impl Payload for Forwarder {
     fn poll_data(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
         let last_chunk;
 
-        if let Some((ref mut body, _)) = self.uploader {
-            match body.poll() {
+        if let Some(ref mut uploader) = self.uploader {
+            if self.awaiting_upload.is_none() {
+                self.awaiting_upload = Some(Instant::now());
+            }
+            match uploader.poll_chunk() {
                 Ok(Async::Ready(None)) => {
-                    // TODO hit EOF, this shouldn't happen anymore
+                    // an empty upload has no last chunk to finish the transfer on
+                    if self.bytes_sent == self.length {
+                        self.handle_last_chunk(&Chunk::from(Vec::new()));
+                    }
                     return Ok(Async::Ready(None));
                 }
                 Ok(Async::Ready(Some(chunk))) => {
                     self.bytes_sent += chunk.len() as u64;
+                    self.last_progress = Instant::now();
+                    if let Some(since) = self.awaiting_upload.take() {
+                        self.upload_wait += self.last_progress - since;
+                    }
 
                     if self.bytes_sent < self.length {
+                        if let Some(ref mut capture) = self.capture {
+                            capture.content.extend_from_slice(&chunk);
+                        }
                         return Ok(Async::Ready(Some(chunk)));
                     } else if self.bytes_sent > self.length {
                         // TODO report an error? (to uploader and downloader)
@@ -219,13 +667,13 @@ impl Payload for Forwarder {
             return Ok(Async::Ready(None));
         }
 
-        self.handle_last_chunk();
+        self.handle_last_chunk(&last_chunk);
         Ok(Async::Ready(Some(last_chunk)))
     }
 
     fn is_end_stream(&self) -> bool {
-        if let Some((ref body, _)) = self.uploader {
-            body.is_end_stream()
+        if let Some(ref uploader) = self.uploader {
+            uploader.is_end_stream()
         } else {
             true
         }
@@ -238,17 +686,189 @@ impl Payload for Forwarder {
 
 #[cfg_attr(debug_assertions, derive(Debug))]
 struct Paste {
-    secret: String,
+    secret_hash: [u8; 32],
     length: u64,
+    // Whether the uploader asked for the content to be kept on the server once it has been sent.
+    cache: bool,
+    // The coding the content was compressed with by the uploader, if any. Length is of that.
+    encoding: Option<Encoding>,
     expiration: Instant,
+    // How long it lives without being downloaded, as configured when it was made.
+    timeout: Duration,
+    uploaders: VecDeque<Forwarder>,
+    // Where to ask for content when no upload is waiting, if the uploader has a socket open.
+    socket: Option<sync::mpsc::Sender<SocketRequest>>,
+    // Uploaders long-polling for a downloader, to be handed a transfer token when one arrives.
+    waiters: VecDeque<sync::oneshot::Sender<String>>,
+    // Downloaders waiting for the upload bound to their transfer token.
+    transfers: HashMap<String, sync::oneshot::Sender<Forwarder>>,
+    // The transfer latecomers can still attach to, when broadcasting.
+    broadcast: Option<broadcast::Joiner<RendezvousPayload>>,
+    events: Events,
+    // The files of a bundle, each with uploads of its own. Empty for a plain paste, which is the
+    // only kind uploaders can serve over a socket or the pull model.
+    files: Vec<BundleFile>,
+}
+
+#[cfg_attr(debug_assertions, derive(Debug))]
+struct BundleFile {
+    entry: bundle::Entry,
     uploaders: VecDeque<Forwarder>,
 }
 
-type BoxFut = Box<Future<Item = Response<RendezvousPayload>, Error = hyper::Error> + Send>;
+// What to check on when a deadline in the expiry queue passes.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Deadline {
+    Warn(String),
+    Expire(String),
+    // a pairing mailbox, by nameplate
+    Unpair(u32),
+}
+
+type BoxFut = Box<dyn Future<Item = Response<RendezvousPayload>, Error = hyper::Error> + Send>;
 // We usually don't care about Ok vs Err here, Err just lets us exit early with ?
 type BoxFutRes = Result<BoxFut, BoxFut>;
 
-type InFlightMap = Arc<Mutex<HashMap<String, Paste>>>;
+// Number of independently locked maps the pastes are spread across, so that operations on
+// different ids (including the download retry loop) rarely contend.
+const SHARDS: usize = 64;
+
+struct InFlight {
+    shards: Vec<Mutex<HashMap<String, Paste>>>,
+    hasher: RandomState,
+    expiry: Arc<ExpiryQueue<Deadline>>,
+    // Set once shutdown begins, to the time by which the process will have exited.
+    drain_deadline: RwLock<Option<Instant>>,
+    // Whether any paste was added, removed or refreshed since the state file was last written.
+    dirty: AtomicBool,
+    // Transfers handed to downloaders, for the watchdog to check on.
+    transfers: Mutex<Vec<Weak<Mutex<Forwarder>>>>,
+    // Transfers currently reading below the minimum throughput, and how many were aborted for it.
+    slow_transfers: AtomicUsize,
+    stalled_transfers: AtomicUsize,
+    cache: Arc<Cache>,
+    pairings: Pairings,
+}
+
+impl InFlight {
+    fn new() -> InFlight {
+        InFlight {
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+            expiry: Arc::new(ExpiryQueue::new()),
+            drain_deadline: RwLock::new(None),
+            dirty: AtomicBool::new(false),
+            transfers: Mutex::new(Vec::new()),
+            slow_transfers: AtomicUsize::new(0),
+            stalled_transfers: AtomicUsize::new(0),
+            cache: Arc::new(Cache::new(config().cache_size_bytes)),
+            pairings: Pairings::default(),
+        }
+    }
+
+    fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+
+    // Refuse new work while shutting down, so only transfers already underway hold us up.
+    fn check_draining(&self) -> Result<(), BoxFut> {
+        match *self.drain_deadline.read().unwrap() {
+            Some(deadline) => Err(Box::new(future::ok(unavailable_response(deadline)))),
+            None => Ok(()),
+        }
+    }
+
+    fn begin_drain(&self, deadline: Instant) {
+        *self.drain_deadline.write().unwrap() = Some(deadline);
+
+        // Nobody will be able to pick these up anymore, let the uploaders know right away.
+        // Event streams would otherwise hold the server open until the deadline.
+        for shard in &self.shards {
+            for paste in shard.lock().unwrap().values_mut() {
+                for forwarder in paste.uploaders.drain(..) {
+                    forwarder.reject(unavailable_response(deadline));
+                }
+                for file in &mut paste.files {
+                    for forwarder in file.uploaders.drain(..) {
+                        forwarder.reject(unavailable_response(deadline));
+                    }
+                }
+                // dropping these answers the waiting side with a 503
+                paste.waiters.clear();
+                paste.transfers.clear();
+                paste.events.close();
+            }
+        }
+        self.pairings.clear();
+    }
+
+    // Lock the shard holding the given id. Only one shard should be locked at a time.
+    fn shard<'a>(&'a self, id: &str) -> MutexGuard<'a, HashMap<String, Paste>> {
+        self.shards[self.hasher.hash_one(id) as usize % SHARDS]
+            .lock()
+            .unwrap()
+    }
+
+    // Push back the expiration of a paste, must be called with the lock on its shard held so the
+    // deadline can't be scheduled after the paste has already been replaced.
+    fn refresh(&self, id: &str, paste: &mut Paste) {
+        paste.expiration = Instant::now() + paste.timeout;
+        self.schedule_expiry(id, paste.expiration);
+        self.mark_dirty();
+    }
+
+    fn schedule_expiry(&self, id: &str, expiration: Instant) {
+        let warning_secs = config().expiry_warning_secs;
+        let warning = Duration::from_secs(warning_secs);
+        if warning_secs > 0 && expiration > Instant::now() + warning {
+            self.expiry
+                .schedule(Deadline::Warn(id.to_owned()), expiration - warning);
+        }
+        self.expiry
+            .schedule(Deadline::Expire(id.to_owned()), expiration);
+    }
+}
+
+type InFlightMap = Arc<InFlight>;
+
+fn unavailable_response(deadline: Instant) -> Response<RendezvousPayload> {
+    let now = Instant::now();
+    let retry_after = if deadline > now {
+        (deadline - now).as_secs() + 1
+    } else {
+        1
+    };
+
+    ApiError::ShuttingDown(retry_after).response()
+}
+
+// When a paste will expire if nothing more happens to it, as a Unix time, on the responses that
+// create or download it. Not Expires, which would tell caches they may keep a download until then.
+pub static EXPIRES_HEADER: &str = "x-rendezvous-expires";
+
+pub fn expires_value(expiration: Instant) -> HeaderValue {
+    let expires = (SystemTime::now() + expiration.saturating_duration_since(Instant::now()))
+        .duration_since(UNIX_EPOCH)
+        .unwrap();
+    HeaderValue::from(expires.as_secs())
+}
+
+// Marks a successful download with when the paste now expires, as downloading pushes that back.
+fn with_expiry(
+    mut response: Response<RendezvousPayload>,
+    id: &str,
+    in_flight: &InFlightMap,
+) -> Response<RendezvousPayload> {
+    if response.status() != StatusCode::OK && response.status() != StatusCode::PARTIAL_CONTENT {
+        return response;
+    }
+    if let Some(paste) = in_flight.shard(id).get(id) {
+        response
+            .headers_mut()
+            .insert(EXPIRES_HEADER, expires_value(paste.expiration));
+    }
+    response
+}
 
 macro_rules! std_response {
     ($t:expr, $s:expr) => {{
@@ -267,420 +887,2386 @@ macro_rules! status_response {
     }};
 }
 
-fn service_home() -> BoxFutRes {
-    Ok(std_response!(TYPE_HTML, UPLOADER_HTML))
+// Runs f where it may block, as reading assets from disk can, without holding up other requests.
+fn blocking<T, F: Fn() -> T>(f: F) -> impl Future<Item = T, Error = ()> {
+    future::poll_fn(move || tokio_threadpool::blocking(&f))
+        .map_err(|e| eprintln!("blocking error: {}", e))
 }
 
-fn service_favicon() -> BoxFutRes {
-    Ok(std_response!("image/x-icon", FAVICON))
+// Always checked for changes, as it is filled in from the config.
+fn service_home(headers: &HeaderMap) -> BoxFutRes {
+    let headers = headers.clone();
+    Ok(Box::new(blocking(|| ASSETS.get("uploader.html")).then(
+        move |page| -> BoxFut {
+            let page = match page {
+                Ok(page) => fill_urls(&String::from_utf8_lossy(&page.content)),
+                Err(()) => return fail(ApiError::Internal),
+            };
+            Box::new(future::ok(assets::response(
+                Asset::new(Bytes::from(page)),
+                TYPE_HTML,
+                0,
+                &headers,
+            )))
+        },
+    )))
 }
 
-fn service_js() -> BoxFutRes {
-    Ok(std_response!(
-        "application/javascript; charset=utf-8",
-        CLIENT_JS
-    ))
+fn service_asset(name: &str, headers: &HeaderMap) -> BoxFutRes {
+    let config = config();
+    let max_age = if config.assets_reload {
+        0
+    } else {
+        config.assets_max_age_secs
+    };
+    let content_type = assets::content_type(name);
+    let name = name.to_owned();
+    let headers = headers.clone();
+    let load = move || match name.strip_prefix("/static/") {
+        Some(name) => ASSETS.get_static(name),
+        None => Some(ASSETS.get(&name[1..])),
+    };
+    Ok(Box::new(blocking(load).then(move |asset| -> BoxFut {
+        match asset {
+            Ok(Some(asset)) => Box::new(future::ok(assets::response(
+                asset,
+                content_type,
+                max_age,
+                &headers,
+            ))),
+            Ok(None) => fail(ApiError::NotFound),
+            Err(()) => fail(ApiError::Internal),
+        }
+    })))
 }
 
-fn generate_id_pair() -> (String, String) {
-    let gen = || {
-        let mut rng = thread_rng();
-        iter::repeat_with(|| rng.choose(BASE58).unwrap())
-            .take(CONFIG.token_length)
-            .collect::<String>()
-    };
-    (gen(), gen())
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
 }
 
-fn query_id(uri: &Uri, only: bool) -> Result<String, BoxFut> {
-    let mut id = None;
+// Where the server is mounted, for the pages and scripts it serves.
+fn fill_urls(template: &str) -> String {
+    let config = config();
+    template
+        .replace("{base_path}", &escape_html(&config.base_path))
+        .replace(
+            "{public_url}",
+            &escape_html(config.public_url.as_deref().unwrap_or("")),
+        )
+}
 
-    if let Some(s) = uri.query() {
-        for (k, v) in url::form_urlencoded::parse(s.as_ref()) {
-            match k.as_ref() {
-                "id" => id = Some(v.into_owned()),
-                _ if only => {
-                    return Err(status_response!(
-                        StatusCode::BAD_REQUEST,
-                        TYPE_HTML,
-                        "<b>Supported arguments id \"id\"</b>"
-                    ));
-                }
-                _ => {}
-            }
-        }
+// The page template filled in with a plain text title and content that is already HTML. Split at
+// the content first so nothing in it is taken for a placeholder.
+fn render_page(title: &str, content: &str) -> String {
+    let title = escape_html(title);
+    // a replacement without a place for the content is no use
+    let page = match String::from_utf8(ASSETS.get("page.html").content.to_vec()) {
+        Ok(page) if page.contains("{content}") => page,
+        _ => String::from_utf8(assets::embedded("page.html").unwrap().to_vec()).unwrap(),
     };
+    let (head, tail) = page.split_at(page.find("{content}").unwrap());
+    format!(
+        "{}{}{}",
+        fill_urls(&head.replace("{title}", &title)),
+        content,
+        fill_urls(&tail["{content}".len()..].replace("{title}", &title))
+    )
+}
 
-    if id.is_none() {
-        return Err(status_response!(
-            StatusCode::BAD_REQUEST,
-            TYPE_HTML,
-            "<b>Id missing</b>"
-        ));
+// An absolute link to path on this server, for sharing. Without public_url, it's made from what a
+// reverse proxy says the client asked for, or else from the host the request was sent to.
+fn public_link(uri: &Uri, headers: &HeaderMap, path: &str) -> String {
+    let config = config();
+    if let Some(ref public_url) = config.public_url {
+        return format!("{}{}", public_url, path);
     }
 
-    Ok(id.unwrap())
+    let host = forwarded(headers, "host", "x-forwarded-host")
+        .or_else(|| headers.get(header::HOST).and_then(|h| h.to_str().ok()))
+        .or_else(|| uri.authority_part().map(|a| a.as_str()))
+        .unwrap_or("localhost");
+    let scheme = match forwarded(headers, "proto", "x-forwarded-proto") {
+        Some(proto) if proto.eq_ignore_ascii_case("https") => "https",
+        Some(_) => "http",
+        None if config.tls_cert_file.is_some() => "https",
+        None => "http",
+    };
+    format!("{}://{}{}{}", scheme, host, config.base_path, path)
 }
 
-fn query_id_and_secret(uri: &Uri, only: bool) -> Result<(String, String), BoxFut> {
-    let mut id = None;
-    let mut secret = None;
-
-    if let Some(s) = uri.query() {
-        for (k, v) in url::form_urlencoded::parse(s.as_ref()) {
-            match k.as_ref() {
-                "id" => id = Some(v.into_owned()),
-                "secret" => secret = Some(v.into_owned()),
-                _ if only => {
-                    return Err(status_response!(
-                        StatusCode::BAD_REQUEST,
-                        TYPE_TEXT,
-                        "Supported arguments are \"id\" and \"secret\""
-                    ));
+// A parameter of the Forwarded header, or the X-Forwarded-* header proxies more often send, as the
+// proxy nearest the client gave it.
+fn forwarded<'a>(headers: &'a HeaderMap, parameter: &str, x_header: &str) -> Option<&'a str> {
+    let standard = headers
+        .get(header::FORWARDED)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| {
+            h.split(',').next()?.split(';').find_map(|pair| {
+                let (name, value) = pair.split_at(pair.find('=')?);
+                if name.trim().eq_ignore_ascii_case(parameter) {
+                    Some(value[1..].trim().trim_matches('"'))
+                } else {
+                    None
                 }
-                _ => {}
-            }
-        }
-    };
+            })
+        });
+    standard
+        .or_else(|| headers.get(x_header)?.to_str().ok()?.split(',').next())
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+}
 
-    let id = if let Some(t) = id {
-        t
-    } else {
-        return Err(status_response!(
-            StatusCode::BAD_REQUEST,
-            TYPE_TEXT,
-            "Missing id"
-        ));
+// Takes base_path off the front of the request's path, so routing and handlers don't have to know
+// about it. False if the request isn't for anything under it.
+fn strip_base_path(req: &mut Request<Body>) -> bool {
+    let config = config();
+    if config.base_path.is_empty() {
+        return true;
+    }
+
+    let path = match req.uri().path().strip_prefix(config.base_path.as_str()) {
+        Some("") => "/",
+        Some(path) if path.starts_with('/') => path,
+        _ => return false,
     };
-    let secret = if let Some(s) = secret {
-        s
-    } else {
-        return Err(status_response!(
-            StatusCode::BAD_REQUEST,
-            TYPE_TEXT,
-            "Missing secret"
-        ));
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_owned(),
     };
 
-    Ok((id, secret))
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = path_and_query.parse().ok();
+    match Uri::from_parts(parts) {
+        Ok(uri) => {
+            *req.uri_mut() = uri;
+            true
+        }
+        Err(_) => false,
+    }
 }
 
-fn query_length(uri: &Uri, only: bool) -> Result<u64, BoxFut> {
-    let mut length = None;
+// Errors for browsers.
+pub fn error_page_html(status: StatusCode, message: &str) -> String {
+    let title = format!(
+        "{} {}",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or("Error")
+    );
+    render_page(&title, &format!("<p>{}</p>\n", escape_html(message)))
+}
+
+fn fail(error: ApiError) -> BoxFut {
+    Box::new(future::ok(error.response()))
+}
+
+fn format_size(length: u64) -> String {
+    let units = ["KiB", "MiB", "GiB", "TiB"];
+    if length == 1 {
+        return "1 byte".to_owned();
+    } else if length < 1024 {
+        return format!("{} bytes", length);
+    }
+    let mut size = length as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {} ({} bytes)", size, units[unit], length)
+}
+
+fn format_duration(secs: u64) -> String {
+    let plural = |n: u64, unit: &str| format!("{} {}{}", n, unit, if n == 1 { "" } else { "s" });
+    match secs {
+        0..=59 => plural(secs, "second"),
+        60..=3599 => plural(secs / 60, "minute"),
+        3600..=86399 => format!(
+            "{} {}",
+            plural(secs / 3600, "hour"),
+            plural(secs % 3600 / 60, "minute")
+        ),
+        _ => format!(
+            "{} {}",
+            plural(secs / 86400, "day"),
+            plural(secs % 86400 / 3600, "hour")
+        ),
+    }
+}
+
+// The id in a landing page path, "/p/<id>", or a v2 API one, "/2/pastes/<id>", as typed.
+pub fn path_id(path: &str) -> Option<String> {
+    let id = match path.strip_prefix("/p/") {
+        Some(id) => id,
+        None => path.strip_prefix("/2/pastes/")?.split('/').next()?,
+    };
+    let id = url::percent_encoding::percent_decode(id.as_bytes()).decode_utf8_lossy();
+    if id.is_empty() {
+        None
+    } else {
+        Some(id.into_owned())
+    }
+}
+
+// A page for whoever opens a link to a paste in a browser: what it is, whether it can be
+// downloaded right now, and buttons to do so.
+fn service_landing(path: &str, in_flight: &InFlightMap) -> BoxFutRes {
+    let id = match path_id(path) {
+        Some(id) => normalize_id(id),
+        None => return service_not_found(),
+    };
+
+    let (length, encoding, expires_in, files, connected) = {
+        let mut shard = in_flight.shard(&id);
+        let paste = match shard.get_mut(&id) {
+            Some(p) => p,
+            None => return Err(fail(ApiError::UnknownId)),
+        };
+        skip_canceled(&mut paste.uploaders);
+        for file in &mut paste.files {
+            skip_canceled(&mut file.uploaders);
+        }
+        let connected = !paste.uploaders.is_empty()
+            || paste.socket.is_some()
+            || !paste.waiters.is_empty()
+            || paste.broadcast.as_ref().is_some_and(|b| b.is_joinable())
+            || paste.files.iter().any(|f| !f.uploaders.is_empty());
+        let files: Vec<bundle::Entry> = paste.files.iter().map(|f| f.entry.clone()).collect();
+        let expires_in = paste
+            .expiration
+            .saturating_duration_since(Instant::now())
+            .as_secs();
+        (paste.length, paste.encoding, expires_in, files, connected)
+    };
+    let cached = in_flight.cache.contains(&id);
+
+    let download = format!(
+        "{}/1/file/download?id={}",
+        config().base_path,
+        url::form_urlencoded::byte_serialize(id.as_bytes()).collect::<String>()
+    );
+
+    let kind = match (files.len(), encoding) {
+        (0, None) => "File".to_owned(),
+        (0, Some(e)) => format!("File, compressed with {}", e.name()),
+        (n, _) => format!("Bundle of {} files", n),
+    };
+    let availability = if cached {
+        "<span class='ok'>Kept on the server, ready to download</span>"
+    } else if connected {
+        "<span class='ok'>Uploader connected, ready to download</span>"
+    } else {
+        "<span class='missing'>No uploader connected right now, try again later</span>"
+    };
+
+    let mut content = format!(
+        "<dl>\n\
+         <dt>Size</dt><dd>{}</dd>\n\
+         <dt>Type</dt><dd>{}</dd>\n\
+         <dt>Expires in</dt><dd>{}</dd>\n\
+         <dt>Availability</dt><dd>{}</dd>\n\
+         </dl>\n",
+        format_size(length),
+        escape_html(&kind),
+        format_duration(expires_in),
+        availability
+    );
+
+    if files.is_empty() {
+        content.push_str(&format!(
+            "<p><a class='button' href='{0}'>Download raw</a>\n\
+             <button id='preview-button' data-src='{0}'>Show preview</button></p>\n",
+            escape_html(&download)
+        ));
+        if !cached {
+            content.push_str(
+                "<p class='note'>Showing the preview downloads the paste, \
+                 which uses up one upload.</p>\n",
+            );
+        }
+        content.push_str(&format!(
+            "<pre id='preview' hidden></pre>\n\
+             <script src='{}/landing.js'></script>\n",
+            escape_html(&config().base_path)
+        ));
+    } else {
+        content.push_str("<ul>\n");
+        for file in &files {
+            let link = format!(
+                "{}&file={}",
+                download,
+                url::form_urlencoded::byte_serialize(file.name.as_bytes()).collect::<String>()
+            );
+            content.push_str(&format!(
+                "<li><a href='{}'>{}</a> ({})</li>\n",
+                escape_html(&link),
+                escape_html(&file.name),
+                format_size(file.length)
+            ));
+        }
+        content.push_str(&format!(
+            "</ul>\n\
+             <p><a class='button' href='{0}'>Download as zip</a>\n\
+             <a class='button' href='{0}&amp;format=tar'>Download as tar</a></p>\n",
+            escape_html(&download)
+        ));
+    }
+
+    Ok(std_response!(
+        TYPE_HTML,
+        render_page(&format!("Paste {}", id), &content)
+    ))
+}
+
+// Where to type a pairing code from another device, which pair.js swaps for the paste it names.
+fn service_receive() -> BoxFutRes {
+    let content = format!(
+        "<form id='receive-form'>\n\
+         <p><input id='receive-code' placeholder='7-guitar-ocean' autocomplete='off' \
+         autofocus>\n\
+         <button id='receive-button'>Receive</button></p>\n\
+         <p id='receive-status' class='note'>Type the code shown next to the paste on the \
+         other device.</p>\n\
+         </form>\n\
+         <script src='{}/pair.js'></script>\n",
+        escape_html(&config().base_path)
+    );
+    Ok(std_response!(
+        TYPE_HTML,
+        render_page("Receive a paste", &content)
+    ))
+}
+
+fn generate_token() -> String {
+    let mut rng = thread_rng();
+    iter::repeat_with(|| rng.choose(BASE58).unwrap())
+        .take(config().token_length)
+        .collect()
+}
+
+fn generate_id() -> String {
+    let config = config();
+    match config.id_scheme {
+        IdScheme::Base58 => generate_token(),
+        IdScheme::Numeric => {
+            let mut rng = thread_rng();
+            iter::repeat_with(|| char::from(b'0' + rng.gen_range(0, 10)))
+                .take(config.token_length)
+                .collect()
+        }
+        IdScheme::Words => words::generate(config.id_words),
+    }
+}
+
+fn generate_id_pair(cluster: &Cluster) -> (String, String) {
+    (cluster.qualify_id(generate_id()), generate_token())
+}
+
+// The id as it was handed out, from however the downloader typed it: word ids in any case and with
+// any separators or a small typo in a word, and numeric ids with spaces or dashes. A cluster node
+// prefix is kept as it is.
+fn normalize_id(id: String) -> String {
+    let (node, token) = match id.rfind('.') {
+        Some(dot) => id.split_at(dot + 1),
+        None => ("", id.as_str()),
+    };
+
+    let config = config();
+    let token = match config.id_scheme {
+        IdScheme::Base58 => return id,
+        IdScheme::Numeric => {
+            let digits: String = token.chars().filter(|c| *c != ' ' && *c != '-').collect();
+            if !digits.chars().all(|c| c.is_ascii_digit()) {
+                return id;
+            }
+            digits
+        }
+        IdScheme::Words => match words::normalize(token, config.id_words) {
+            Some(t) => t,
+            None => return id,
+        },
+    };
+    format!("{}{}", node, token)
+}
+
+// Only a hash of the secret is kept, so the state file doesn't hand out control of every paste.
+// Secrets are kept as HMAC-SHA256 of their SHA-256, which older state files saved as it is.
+fn hash_secret(secret: &str) -> [u8; 32] {
+    keyed_hash(&Sha256::digest(secret.as_bytes()))
+}
+
+fn keyed_hash(digest: &[u8]) -> [u8; 32] {
+    // the key is padded with zeros to SHA-256's block size
+    let mut block = [0; 64];
+    block[..32].copy_from_slice(&SECRET_KEY.key);
+
+    let mut inner = Sha256::new();
+    inner.input(block.iter().map(|b| b ^ 0x36).collect::<Vec<u8>>());
+    inner.input(digest);
+    let mut outer = Sha256::new();
+    outer.input(block.iter().map(|b| b ^ 0x5c).collect::<Vec<u8>>());
+    outer.input(inner.result());
+
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&outer.result());
+    hash
+}
+
+fn check_secret(paste: &Paste, secret: &str) -> bool {
+    same_bytes(&paste.secret_hash, &hash_secret(secret))
+}
+
+// Compares in the same time wherever they differ, so the time taken gives nothing away.
+fn same_bytes(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |d, (a, b)| d | (a ^ b)) == 0
+}
+
+fn query_id(uri: &Uri, only: bool) -> Result<String, BoxFut> {
+    let mut id = None;
+
+    if let Some(s) = uri.query() {
+        for (k, v) in url::form_urlencoded::parse(s.as_ref()) {
+            match k.as_ref() {
+                "id" => id = Some(normalize_id(v.into_owned())),
+                _ if only => {
+                    return Err(fail(ApiError::UnknownArgument(&["id"])));
+                }
+                _ => {}
+            }
+        }
+    };
+
+    if id.is_none() {
+        return Err(fail(ApiError::MissingArgument("id")));
+    }
+
+    Ok(id.unwrap())
+}
+
+fn query_id_and_secret(uri: &Uri, only: bool) -> Result<(String, String), BoxFut> {
+    let mut id = None;
+    let mut secret = None;
+
+    if let Some(s) = uri.query() {
+        for (k, v) in url::form_urlencoded::parse(s.as_ref()) {
+            match k.as_ref() {
+                "id" => id = Some(normalize_id(v.into_owned())),
+                "secret" => secret = Some(v.into_owned()),
+                _ if only => {
+                    return Err(fail(ApiError::UnknownArgument(&["id", "secret"])));
+                }
+                _ => {}
+            }
+        }
+    };
+
+    let id = if let Some(t) = id {
+        t
+    } else {
+        return Err(fail(ApiError::MissingArgument("id")));
+    };
+    let secret = if let Some(s) = secret {
+        s
+    } else {
+        return Err(fail(ApiError::MissingArgument("secret")));
+    };
+
+    Ok((id, secret))
+}
+
+// What the uploader declares about its content when asking for an id.
+struct PasteOptions {
+    length: u64,
+    cache: bool,
+    encoding: Option<Encoding>,
+    // A bundle's manifest, with length their total.
+    files: Vec<bundle::Entry>,
+    // The id the uploader chose, instead of a generated one.
+    name: Option<String>,
+}
+
+fn query_paste_options(uri: &Uri, only: bool) -> Result<PasteOptions, BoxFut> {
+    let mut length = None;
+    let mut cache = false;
+    let mut encoding = None;
+    let mut files: Vec<bundle::Entry> = Vec::new();
+    let mut name = None;
+
+    if let Some(s) = uri.query() {
+        for (k, v) in url::form_urlencoded::parse(s.as_ref()) {
+            match k.as_ref() {
+                "length" => length = Some(v),
+                "cache" => {
+                    cache = match v.as_ref() {
+                        "true" => true,
+                        "false" => false,
+                        _ => {
+                            return Err(fail(ApiError::InvalidArgument("cache", "true or false")));
+                        }
+                    }
+                }
+                "encoding" => {
+                    encoding = match Encoding::parse(&v) {
+                        Some(e) => Some(e),
+                        None => {
+                            return Err(fail(ApiError::InvalidArgument("encoding", "gzip or br")));
+                        }
+                    }
+                }
+                "file" => {
+                    let entry = match bundle::parse_entry(&v) {
+                        Ok(e) => e,
+                        Err(message) => {
+                            return Err(fail(ApiError::InvalidFile(message)));
+                        }
+                    };
+                    if files.iter().any(|f| f.name == entry.name) {
+                        return Err(fail(ApiError::DuplicateFile));
+                    }
+                    if files.len() == bundle::MAX_FILES {
+                        return Err(fail(ApiError::TooManyFiles));
+                    }
+                    files.push(entry);
+                }
+                "name" => {
+                    if !valid_name(&v) {
+                        return Err(fail(ApiError::InvalidArgument("name", NAME_RULES)));
+                    }
+                    // it would be looked up as something else
+                    if normalize_id(v.clone().into_owned()) != v {
+                        return Err(fail(ApiError::NameLooksGenerated));
+                    }
+                    name = Some(v.into_owned());
+                }
+                _ if only => {
+                    return Err(fail(ApiError::UnknownArgument(&[
+                        "length", "cache", "encoding", "file", "name",
+                    ])));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if !files.is_empty() {
+        if length.is_some() || cache || encoding.is_some() {
+            return Err(fail(ApiError::ConflictingArguments));
+        }
+        let length = files.iter().map(|f| f.length).sum();
+        if length > config().max_content_length {
+            return Err(fail(ApiError::LengthTooLarge));
+        }
+        return Ok(PasteOptions {
+            length,
+            cache,
+            encoding,
+            files,
+            name,
+        });
+    }
+
+    let length = if let Some(l) = length {
+        l
+    } else {
+        return Err(fail(ApiError::MissingArgument("length")));
+    };
+    let length = if let Ok(l) = length.parse::<u64>() {
+        l
+    } else {
+        return Err(fail(ApiError::InvalidArgument(
+            "length",
+            "a decimal integer",
+        )));
+    };
+    let config = config();
+    if length > config.max_content_length {
+        return Err(fail(ApiError::LengthTooLarge));
+    }
+    if cache && length > config.cache_size_bytes {
+        return Err(fail(ApiError::LengthTooLargeToCache));
+    }
+
+    Ok(PasteOptions {
+        length,
+        cache,
+        encoding,
+        files,
+        name,
+    })
+}
+
+// What valid_name checks, for whoever chose a name that fails it.
+const NAME_RULES: &str = "3 to 64 letters, digits, \"-\" or \"_\", starting with a letter or digit";
+
+// Letters, digits, '-' and '_', so a chosen id can't be confused with a node prefix or need
+// escaping in a link.
+fn valid_name(name: &str) -> bool {
+    name.len() >= 3
+        && name.len() <= 64
+        && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+// Anyone may choose their id, unless tokens are configured, when it takes one of them as a bearer
+// token.
+fn check_vanity_allowed(headers: &HeaderMap) -> Result<(), BoxFut> {
+    let config = config();
+    if !config.vanity_ids {
+        return Err(fail(ApiError::NamesDisabled));
+    }
+    if config.vanity_id_tokens.is_empty() {
+        return Ok(());
+    }
+
+    // every token is compared, and in constant time, so timing doesn't give any of them away
+    let allowed = bearer_token(headers).is_some_and(|token| {
+        config.vanity_id_tokens.iter().fold(false, |ok, t| {
+            ok | same_bytes(t.as_bytes(), token.as_bytes())
+        })
+    });
+    if allowed {
+        Ok(())
+    } else {
+        Err(fail(ApiError::NameTokenRequired))
+    }
+}
+
+fn service_request_id(
+    uri: &Uri,
+    headers: &HeaderMap,
+    in_flight: &InFlightMap,
+    cluster: &Cluster,
+) -> BoxFutRes {
+    let (id, secret, expiration) = create_paste(uri, headers, in_flight, cluster)?;
+    Ok(Box::new(future::ok(
+        Response::builder()
+            .header(header::CONTENT_TYPE, TYPE_TEXT)
+            .header(EXPIRES_HEADER, expires_value(expiration))
+            .body(Bod(Body::from(id + "," + &secret)))
+            .unwrap(),
+    )))
+}
+
+// A new paste as described by the query, returning its id, secret and when it expires.
+fn create_paste(
+    uri: &Uri,
+    headers: &HeaderMap,
+    in_flight: &InFlightMap,
+    cluster: &Cluster,
+) -> Result<(String, String, Instant), BoxFut> {
+    in_flight.check_draining()?;
+    let config = config();
+    let options = query_paste_options(uri, true)?;
+    if options.name.is_some() {
+        check_vanity_allowed(headers)?;
+    }
+
+    // with a small id space nearly used up, give up rather than spin
+    for _ in 0..MAX_ID_ATTEMPTS {
+        let (id, secret) = match options.name {
+            Some(ref name) => (cluster.qualify_id(name.clone()), generate_token()),
+            None => generate_id_pair(cluster),
+        };
+
+        match in_flight.shard(&id).entry(id) {
+            Entry::Occupied(_) if options.name.is_some() => {
+                return Err(fail(ApiError::NameTaken));
+            }
+            Entry::Occupied(_) => {
+                continue;
+            }
+            Entry::Vacant(entry) => {
+                let id = entry.key().clone();
+                let paste = entry.insert(Paste {
+                    secret_hash: hash_secret(&secret),
+                    length: options.length,
+                    cache: options.cache,
+                    encoding: options.encoding,
+                    expiration: Instant::now(),
+                    timeout: Duration::from_secs(config.timeout_secs),
+                    uploaders: VecDeque::new(),
+                    socket: None,
+                    waiters: VecDeque::new(),
+                    transfers: HashMap::new(),
+                    broadcast: None,
+                    events: Events::default(),
+                    files: options
+                        .files
+                        .into_iter()
+                        .map(|entry| BundleFile {
+                            entry,
+                            uploaders: VecDeque::new(),
+                        })
+                        .collect(),
+                });
+                in_flight.refresh(&id, paste);
+                let expiration = paste.expiration;
+                return Ok((id, secret, expiration));
+            }
+        }
+    }
+
+    Err(fail(ApiError::IdsExhausted))
+}
+
+fn service_retire_id(uri: &Uri, in_flight: &InFlightMap) -> BoxFutRes {
+    let (id, secret) = query_id_and_secret(uri, true)?;
+    retire_paste(id, &secret, in_flight)?;
+    Ok(std_response!(TYPE_TEXT, "Removed"))
+}
+
+fn retire_paste(id: String, secret: &str, in_flight: &InFlightMap) -> Result<(), BoxFut> {
+    match in_flight.shard(&id).entry(id) {
+        Entry::Occupied(mut entry) => {
+            {
+                let paste = entry.get_mut();
+
+                if !check_secret(paste, secret) {
+                    return Err(fail(ApiError::BadSecret));
+                }
+            }
+            let (id, paste) = entry.remove_entry();
+            in_flight.cache.remove(&id);
+            paste.events.send("retired", r#"{"reason":"retired"}"#);
+            paste.events.close();
+            in_flight.mark_dirty();
+            Ok(())
+        }
+        Entry::Vacant(_) => Err(fail(ApiError::UnknownId)),
+    }
+}
+
+// The transfer token an upload is meant for, if any, and the file of a bundle it is of.
+fn query_transfer_and_file(uri: &Uri) -> Result<(Option<String>, Option<String>), BoxFut> {
+    let mut transfer = None;
+    let mut file = None;
+
+    if let Some(s) = uri.query() {
+        for (k, v) in url::form_urlencoded::parse(s.as_ref()) {
+            match k.as_ref() {
+                "transfer" => transfer = Some(v.into_owned()),
+                "file" => file = Some(v.into_owned()),
+                "id" | "secret" => {}
+                _ => {
+                    return Err(fail(ApiError::UnknownArgument(&[
+                        "id", "secret", "transfer", "file",
+                    ])));
+                }
+            }
+        }
+    };
+
+    Ok((transfer, file))
+}
+
+// Long-polled by uploaders using the pull model. Answers with a transfer token once a downloader
+// turns up, to be passed along with the upload meant for it, or with 204 if none did in time.
+fn service_wait(uri: &Uri, in_flight: &InFlightMap) -> BoxFutRes {
+    let (id, secret) = query_id_and_secret(uri, true)?;
+
+    let (waiter, token) = sync::oneshot::channel();
+
+    match in_flight.shard(&id).get_mut(&id) {
+        Some(paste) => {
+            in_flight.check_draining()?;
+
+            if !check_secret(paste, &secret) {
+                return Err(fail(ApiError::BadSecret));
+            }
+            if !paste.files.is_empty() {
+                return Err(fail(ApiError::BundleUploadOnly));
+            }
+            // drop the long-polls that have already given up
+            paste.waiters.retain(|w| !w.is_canceled());
+            paste.waiters.push_back(waiter);
+        }
+        None => {
+            return Err(fail(ApiError::UnknownId));
+        }
+    };
+
+    let in_flight = in_flight.clone();
+    let timeout = Delay::new(Duration::from_secs(config().wait_timeout_secs));
+    Ok(Box::new(token.select2(timeout).then(move |result| {
+        match result {
+            Ok(future::Either::A((token, _))) => std_response!(TYPE_TEXT, token),
+            Ok(future::Either::B(_)) | Err(future::Either::B(_)) => {
+                status_response!(StatusCode::NO_CONTENT, TYPE_TEXT, "")
+            }
+            // the paste is gone, or the server is shutting down
+            Err(future::Either::A(_)) => match in_flight.check_draining() {
+                Err(response) => response,
+                Ok(()) => fail(ApiError::UnknownId),
+            },
+        }
+    })))
+}
+
+fn service_upload(req: Request<Body>, in_flight: &InFlightMap) -> BoxFutRes {
+    let (id, secret) = query_id_and_secret(req.uri(), false)?;
+    upload_paste(req, id, secret, in_flight)
+}
+
+// The rest of the query may name a transfer or a file of a bundle.
+fn upload_paste(
+    req: Request<Body>,
+    id: String,
+    secret: String,
+    in_flight: &InFlightMap,
+) -> BoxFutRes {
+    let (header, body) = req.into_parts();
+
+    let (transfer, file) = query_transfer_and_file(&header.uri)?;
+
+    let encoding = match header.headers.get(header::CONTENT_ENCODING) {
+        None => None,
+        Some(value) => match value.to_str() {
+            Ok(name) if name.trim().eq_ignore_ascii_case("identity") => None,
+            Ok(name) if Encoding::parse(name).is_some() => Encoding::parse(name),
+            _ => {
+                return Err(fail(ApiError::UnsupportedEncoding));
+            }
+        },
+    };
+
+    let length = if let Some(length) = body.content_length() {
+        length
+    } else {
+        return Err(fail(ApiError::LengthRequired));
+    };
+
+    let (complete, completion) = sync::oneshot::channel();
+
+    match in_flight.shard(&id).entry(id) {
+        Entry::Occupied(mut entry) => {
+            // checked with the shard locked so begin_drain can't miss this uploader
+            in_flight.check_draining()?;
+
+            let paste = entry.get_mut();
+            if !check_secret(paste, &secret) {
+                return Err(fail(ApiError::BadSecret));
+            }
+
+            if !paste.files.is_empty() || file.is_some() {
+                return upload_file(paste, file, length, encoding, body, complete)
+                    .map(|()| uploaded(completion));
+            }
+
+            if paste.length != length {
+                return Err(fail(ApiError::WrongLength));
+            }
+            if paste.encoding != encoding {
+                return Err(fail(ApiError::EncodingMismatch));
+            }
+
+            let mut forwarder = Forwarder::new(paste.length, Uploader::Post(body, complete));
+
+            if let Some(transfer) = transfer {
+                // goes straight to the downloader that was promised it
+                let downloader = if let Some(downloader) = paste.transfers.remove(&transfer) {
+                    downloader
+                } else {
+                    return Err(fail(ApiError::UnknownTransfer));
+                };
+                forwarder.events = Some(paste.events.clone());
+                if downloader.send(forwarder).is_err() {
+                    return Err(fail(ApiError::DownloaderGone));
+                }
+            } else {
+                // TODO not sure if we really want someone to be able to
+                // queue up many uploads, actually...
+                // Probably needs at least an upper limit.
+                paste.uploaders.push_back(forwarder);
+            }
+        }
+        Entry::Vacant(_) => {
+            return Err(fail(ApiError::UnknownId));
+        }
+    };
+
+    Ok(uploaded(completion))
+}
+
+// Queue up the upload of one file of a bundle.
+fn upload_file(
+    paste: &mut Paste,
+    file: Option<String>,
+    length: u64,
+    encoding: Option<Encoding>,
+    body: Body,
+    complete: sync::oneshot::Sender<Response<RendezvousPayload>>,
+) -> Result<(), BoxFut> {
+    let file = match file {
+        Some(f) => f,
+        None => {
+            return Err(fail(ApiError::MissingArgument("file")));
+        }
+    };
+    if paste.files.is_empty() {
+        return Err(fail(ApiError::NotABundle));
+    }
+    let bundle_file = match paste.files.iter_mut().find(|f| f.entry.name == file) {
+        Some(f) => f,
+        None => {
+            return Err(fail(ApiError::UnknownFile));
+        }
+    };
+    if bundle_file.entry.length != length {
+        return Err(fail(ApiError::WrongLength));
+    }
+    if encoding.is_some() {
+        return Err(fail(ApiError::EncodingMismatch));
+    }
+
+    bundle_file
+        .uploaders
+        .push_back(Forwarder::new(length, Uploader::Post(body, complete)));
+    Ok(())
+}
+
+// Answers the uploader once its upload has been forwarded, or failed to be.
+fn uploaded(completion: sync::oneshot::Receiver<Response<RendezvousPayload>>) -> BoxFut {
+    // TODO technically we'd want this to be an Err when this fails somehow
+    Box::new(
+        completion
+            .or_else(|_| future::ok(ApiError::Internal.response()))
+            .map_err(|_: sync::oneshot::Canceled| unreachable!()),
+    )
+}
+
+// The (offset, length) asked for by a single "bytes=" range. Ok(None) means the range should be
+// ignored and the whole paste sent, Err that it can't be satisfied.
+fn byte_range(range: &str, total: u64) -> Result<Option<(u64, u64)>, ()> {
+    let spec = match range.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec,
+        _ => return Ok(None),
+    };
+    let (first, last) = match spec.find('-') {
+        Some(dash) => (spec[..dash].trim(), spec[dash + 1..].trim()),
+        None => return Ok(None),
+    };
+
+    let (start, end) = if first.is_empty() {
+        // the last n bytes
+        match last.parse::<u64>() {
+            Ok(0) => return Err(()),
+            Ok(n) => (total.saturating_sub(n), total.saturating_sub(1)),
+            Err(_) => return Ok(None),
+        }
+    } else {
+        let start = match first.parse::<u64>() {
+            Ok(start) => start,
+            Err(_) => return Ok(None),
+        };
+        let end = if last.is_empty() {
+            total.saturating_sub(1)
+        } else {
+            match last.parse::<u64>() {
+                // backwards, which makes the header invalid rather than unsatisfiable
+                Ok(end) if end < start => return Ok(None),
+                Ok(end) => end.min(total.saturating_sub(1)),
+                Err(_) => return Ok(None),
+            }
+        };
+        (start, end)
+    };
+
+    if start >= total || start > end {
+        Err(())
+    } else {
+        Ok(Some((start, end - start + 1)))
+    }
+}
+
+// Ask the uploader's socket, if there is one, for (part of) the paste.
+fn request_from_socket(
+    id: &str,
+    paste: &mut Paste,
+    range: Option<&str>,
+    in_flight: &InFlight,
+) -> Option<Response<RendezvousPayload>> {
+    paste.socket.as_ref()?;
+
+    let range = match range.map(|r| byte_range(r, paste.length)) {
+        Some(Ok(range)) => range,
+        None => None,
+        Some(Err(())) => {
+            return Some(ApiError::RangeNotSatisfiable(paste.length).response());
+        }
+    };
+    let (offset, length) = range.unwrap_or((0, paste.length));
+
+    let (chunks, receiver) = Body::channel();
+    let request = SocketRequest {
+        offset,
+        length,
+        chunks,
+    };
+    if let Err(e) = paste.socket.as_mut().unwrap().try_send(request) {
+        // with too many requests waiting on the uploader, the downloader tries again later
+        if e.is_disconnected() {
+            paste.socket = None;
+        }
+        return None;
+    }
+
+    let forwarder = Forwarder::new(length, Uploader::Socket(receiver));
+
+    let mut response = Response::builder();
+    response.header(header::CONTENT_TYPE, TYPE_TEXT);
+    if range.is_some() {
+        response.status(StatusCode::PARTIAL_CONTENT);
+        response.header(
+            header::CONTENT_RANGE,
+            format!("bytes {}-{}/{}", offset, offset + length - 1, paste.length),
+        );
+    }
+    Some(
+        response
+            .body(forwarder.hand_out(id, paste, range.is_none(), in_flight))
+            .unwrap(),
+    )
+}
+
+// Wake an uploader long-polling /1/file/wait and wait for the upload it sends for us.
+fn request_from_waiter(id: &str, paste: &mut Paste, in_flight: &InFlightMap) -> Option<BoxFut> {
+    let (downloader, upload) = sync::oneshot::channel();
+    let mut transfer = generate_token();
+
+    loop {
+        let waiter = paste.waiters.pop_front()?;
+        while paste.transfers.contains_key(&transfer) {
+            transfer = generate_token();
+        }
+        if waiter.send(transfer.clone()).is_ok() {
+            break;
+        }
+        // that uploader stopped waiting, try the next one
+    }
+    paste.transfers.insert(transfer.clone(), downloader);
+
+    let id = id.to_owned();
+    let in_flight = in_flight.clone();
+    // the woken uploader has to start the upload from scratch, which may take it a while
+    let timeout = Delay::new(Duration::from_secs(config().wait_timeout_secs));
+    Some(Box::new(upload.select2(timeout).then(move |result| {
+        match result {
+            Ok(future::Either::A((forwarder, _))) => Box::new(future::ok(
+                Response::builder()
+                    .header(header::CONTENT_TYPE, TYPE_TEXT)
+                    .body(forwarder.watch(&in_flight))
+                    .unwrap(),
+            )),
+            _ => {
+                // the uploader never came back, don't let it use the token later
+                if let Some(paste) = in_flight.shard(&id).get_mut(&id) {
+                    paste.transfers.remove(&transfer);
+                }
+                match in_flight.check_draining() {
+                    Err(response) => response,
+                    Ok(()) => fail(ApiError::NoUploader),
+                }
+            }
+        }
+    })))
+}
+
+fn cached_response(content: Bytes, range: Option<&str>) -> Response<RendezvousPayload> {
+    let total = content.len() as u64;
+    let mut response = Response::builder();
+    response.header(header::CONTENT_TYPE, TYPE_TEXT);
+
+    match range.map(|r| byte_range(r, total)) {
+        Some(Ok(Some((offset, length)))) => {
+            response.status(StatusCode::PARTIAL_CONTENT);
+            response.header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", offset, offset + length - 1, total),
+            );
+            let content = content.slice(offset as usize, (offset + length) as usize);
+            response.body(Bod(Body::from(content))).unwrap()
+        }
+        Some(Err(())) => ApiError::RangeNotSatisfiable(total).response(),
+        _ => response.body(Bod(Body::from(content))).unwrap(),
+    }
+}
+
+// Which file of a bundle to download, or which archive format to get them all in.
+fn query_bundle_part(uri: &Uri) -> Result<(Option<String>, Option<Format>), BoxFut> {
+    let mut file = None;
+    let mut format = None;
+
+    if let Some(s) = uri.query() {
+        for (k, v) in url::form_urlencoded::parse(s.as_ref()) {
+            match k.as_ref() {
+                "file" => file = Some(v.into_owned()),
+                "format" => {
+                    format = match Format::parse(&v) {
+                        Some(f) => Some(f),
+                        None => {
+                            return Err(fail(ApiError::InvalidArgument("format", "zip or tar")));
+                        }
+                    }
+                }
+                "id" => {}
+                _ => {
+                    return Err(fail(ApiError::UnknownArgument(&["id", "file", "format"])));
+                }
+            }
+        }
+    };
+
+    Ok((file, format))
+}
+
+// Drop uploads from the front of the queue whose uploader has gone away since.
+fn skip_canceled(uploaders: &mut VecDeque<Forwarder>) {
+    while uploaders
+        .front()
+        .is_some_and(|f| f.uploader.as_ref().is_none_or(|u| u.is_canceled()))
+    {
+        uploaders.pop_front();
+    }
+}
+
+// Content-Disposition for a file of a bundle, named after the last part of its path.
+fn attachment(name: &str) -> String {
+    let name = name.rsplit('/').next().unwrap_or(name);
+    let mut disposition = String::from("attachment; filename*=UTF-8''");
+    for b in name.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            disposition.push(b as char);
+        } else {
+            disposition.push_str(&format!("%{:02X}", b));
+        }
+    }
+    disposition
+}
+
+// Hands an archive the next upload of each file as it gets to it.
+fn fetch_file(id: &str, in_flight: &InFlightMap) -> bundle::Fetch<RendezvousPayload> {
+    let id = id.to_owned();
+    let in_flight = in_flight.clone();
+    Box::new(move |index| {
+        let mut shard = in_flight.shard(&id);
+        let paste = shard.get_mut(&id)?;
+        let uploaders = &mut paste.files.get_mut(index)?.uploaders;
+        skip_canceled(uploaders);
+        let forwarder = uploaders.pop_front()?;
+        in_flight.refresh(&id, paste);
+        Some(forwarder.hand_out(&id, paste, false, &in_flight))
+    })
+}
+
+// One file of a bundle, or all of them as an archive once there is an upload of each waiting.
+fn download_from_bundle(
+    id: String,
+    file: Option<String>,
+    format: Format,
+    mut retries: u64,
+    in_flight: InFlightMap,
+) -> BoxFut {
+    if let Err(response) = in_flight.check_draining() {
+        return response;
+    }
+
+    match in_flight.shard(&id).get_mut(&id) {
+        Some(ref paste) if paste.files.is_empty() => {
+            return fail(ApiError::NotABundle);
+        }
+        Some(paste) => match file {
+            Some(ref name) => {
+                let uploaders = match paste.files.iter_mut().find(|f| &f.entry.name == name) {
+                    Some(f) => &mut f.uploaders,
+                    None => {
+                        return fail(ApiError::UnknownFile);
+                    }
+                };
+                skip_canceled(uploaders);
+                if let Some(forwarder) = uploaders.pop_front() {
+                    in_flight.refresh(&id, paste);
+                    let payload = forwarder.hand_out(&id, paste, false, &in_flight);
+                    return Box::new(future::ok(
+                        Response::builder()
+                            .header(header::CONTENT_TYPE, "application/octet-stream")
+                            .header(header::CONTENT_DISPOSITION, attachment(name))
+                            .body(payload)
+                            .unwrap(),
+                    ));
+                }
+            }
+            None => {
+                let entries: Vec<bundle::Entry> =
+                    paste.files.iter().map(|f| f.entry.clone()).collect();
+                if !bundle::fits(format, &entries) {
+                    return fail(ApiError::TooLargeForZip);
+                }
+
+                let ready = paste.files.iter_mut().all(|f| {
+                    skip_canceled(&mut f.uploaders);
+                    !f.uploaders.is_empty()
+                });
+                if ready {
+                    in_flight.refresh(&id, paste);
+                    let config = config();
+                    let archive = bundle::Archive::new(
+                        format,
+                        entries,
+                        fetch_file(&id, &in_flight),
+                        Duration::from_millis(config.download_retry_ms),
+                        config.download_max_retries,
+                    );
+                    return Box::new(future::ok(
+                        Response::builder()
+                            .header(header::CONTENT_TYPE, format.content_type())
+                            .header(
+                                header::CONTENT_DISPOSITION,
+                                attachment(&format!("{}.{}", id, format.extension())),
+                            )
+                            .body(Bun(Box::new(archive)))
+                            .unwrap(),
+                    ));
+                }
+            }
+        },
+        None => {
+            return fail(ApiError::UnknownId);
+        }
+    };
+
+    Box::new(
+        Delay::new(Duration::from_millis(config().download_retry_ms))
+            .or_else(|_| future::ok(()))
+            .and_then(move |_| {
+                if retries > 0 {
+                    retries -= 1;
+                    download_from_bundle(id, file, format, retries, in_flight)
+                } else {
+                    fail(ApiError::NoUploader)
+                }
+            }),
+    )
+}
+
+fn service_download(uri: &Uri, headers: &HeaderMap, in_flight: &InFlightMap) -> BoxFutRes {
+    let id = query_id(uri, false)?;
+    download_paste(id, uri, headers, in_flight)
+}
+
+// The rest of the query picks a file or archive format for bundles.
+fn download_paste(
+    id: String,
+    uri: &Uri,
+    headers: &HeaderMap,
+    in_flight: &InFlightMap,
+) -> BoxFutRes {
+    let (file, format) = query_bundle_part(uri)?;
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|r| r.to_str().ok())
+        .map(String::from);
+
+    // TODO refactor for less ugliness
+    fn download(
+        id: String,
+        range: Option<String>,
+        mut retries: u64,
+        in_flight: InFlightMap,
+    ) -> BoxFut {
+        if let Err(response) = in_flight.check_draining() {
+            return response;
+        }
+
+        match in_flight.shard(&id).entry(id.clone()) {
+            Entry::Occupied(mut entry) => {
+                let paste = entry.get_mut();
+
+                if paste.cache {
+                    if let Some(content) = in_flight.cache.get(&id) {
+                        in_flight.refresh(&id, paste);
+                        return Box::new(future::ok(cached_response(content, range.as_deref())));
+                    }
+                }
+
+                if range.is_none() {
+                    if let Some(tap) = paste.broadcast.as_ref().and_then(|b| b.join()) {
+                        in_flight.refresh(&id, paste);
+                        return Box::new(future::ok(
+                            Response::builder()
+                                .header(header::CONTENT_TYPE, TYPE_TEXT)
+                                .body(Tap(tap))
+                                .unwrap(),
+                        ));
+                    }
+                }
+
+                match paste.uploaders.pop_front() {
+                    Some(forwarder)
+                        if forwarder
+                            .uploader
+                            .as_ref()
+                            .is_some_and(|u| !u.is_canceled()) =>
+                    {
+                        in_flight.refresh(&id, paste);
+                        let payload = forwarder.hand_out(&id, paste, true, &in_flight);
+                        return Box::new(future::ok(
+                            Response::builder()
+                                .header(header::CONTENT_TYPE, TYPE_TEXT)
+                                .body(payload)
+                                .unwrap(),
+                        ));
+                    }
+                    _ => {
+                        // try the socket, or fall through to retry
+                    }
+                }
+
+                if let Some(response) =
+                    request_from_socket(&id, paste, range.as_deref(), &in_flight)
+                {
+                    in_flight.refresh(&id, paste);
+                    return Box::new(future::ok(response));
+                }
+
+                if let Some(response) = request_from_waiter(&id, paste, &in_flight) {
+                    in_flight.refresh(&id, paste);
+                    return response;
+                }
+            }
+            Entry::Vacant(_) => {
+                return fail(ApiError::UnknownId);
+            }
+        };
+
+        Box::new(
+            Delay::new(Duration::from_millis(config().download_retry_ms))
+                .or_else(|_| future::ok(())) // TODO probably should not retry on timer errors?
+                .and_then(move |_| {
+                    if retries > 0 {
+                        retries -= 1;
+                        download(id, range, retries, in_flight)
+                    } else {
+                        fail(ApiError::NoUploader)
+                    }
+                }),
+        )
+    }
+
+    // let the uploader know someone is waiting, even if it has nothing ready for them yet
+    let (stored, is_bundle) = match in_flight.shard(&id).get(&id) {
+        Some(paste) => {
+            paste.events.send("downloader", "{}");
+            (paste.encoding, !paste.files.is_empty())
+        }
+        None => (None, false),
+    };
+
+    let config = config();
+    if is_bundle || file.is_some() || format.is_some() {
+        // ranges aren't supported for bundles, so anything can be compressed
+        let coding = encoding::negotiate(None, headers, config.compress_downloads);
+        let (paste_id, in_flight) = (id.clone(), in_flight.clone());
+        return Ok(Box::new(
+            download_from_bundle(
+                id,
+                file,
+                format.unwrap_or(Format::Zip),
+                config.download_max_retries,
+                in_flight.clone(),
+            )
+            .map(move |response| encode_response(response, coding))
+            .map(move |response| with_expiry(response, &paste_id, &in_flight)),
+        ));
+    }
+
+    let coding = encoding::negotiate(
+        stored,
+        headers,
+        config.compress_downloads && range.is_none(),
+    );
+    // a range of compressed content is no use to someone who needs it decompressed
+    let range = match coding {
+        Coding::Decompress(_) => None,
+        _ => range,
+    };
+
+    let (paste_id, in_flight) = (id.clone(), in_flight.clone());
+    Ok(Box::new(
+        download(id, range, config.download_max_retries, in_flight.clone())
+            .map(move |response| encode_response(response, coding))
+            .map(move |response| with_expiry(response, &paste_id, &in_flight)),
+    ))
+}
+
+// Sends content in the coding negotiated with the downloader. Error pages are left as they are.
+fn encode_response(
+    response: Response<RendezvousPayload>,
+    coding: Coding,
+) -> Response<RendezvousPayload> {
+    if response.status() != StatusCode::OK && response.status() != StatusCode::PARTIAL_CONTENT {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    parts
+        .headers
+        .insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+    if let Some(value) = coding.content_encoding() {
+        parts.headers.insert(header::CONTENT_ENCODING, value);
+    }
+
+    let body = match coding {
+        Coding::Stored(_) => body,
+        Coding::Compress(e) => Enc(Box::new(encoding::Encoded::compress(body, e))),
+        Coding::Decompress(e) => Enc(Box::new(encoding::Encoded::decompress(body, e))),
+    };
+    Response::from_parts(parts, body)
+}
+
+// The manifest of a bundle, with a line of "length name" per file.
+fn service_index(uri: &Uri, in_flight: &InFlightMap) -> BoxFutRes {
+    let id = query_id(uri, true)?;
+
+    match in_flight.shard(&id).get(&id) {
+        Some(paste) if paste.files.is_empty() => Err(fail(ApiError::NotABundle)),
+        Some(paste) => {
+            let index: String = paste
+                .files
+                .iter()
+                .map(|f| format!("{} {}\n", f.entry.length, f.entry.name))
+                .collect();
+            Ok(std_response!(TYPE_TEXT, index))
+        }
+        None => Err(fail(ApiError::UnknownId)),
+    }
+}
+
+// The nameplate of a pairing code, and the token of the side asking, where needed. The words of
+// the code are refused, so a client getting it wrong can't leak them.
+fn query_pairing(uri: &Uri, with_token: bool) -> Result<(u32, String), BoxFut> {
+    let mut code = None;
+    let mut token = None;
 
     if let Some(s) = uri.query() {
         for (k, v) in url::form_urlencoded::parse(s.as_ref()) {
             match k.as_ref() {
-                "length" => length = Some(v),
-                _ if only => {
-                    return Err(status_response!(
-                        StatusCode::BAD_REQUEST,
-                        TYPE_TEXT,
-                        "Supported argument is \"length\""
-                    ));
+                "code" => code = Some(v.into_owned()),
+                "token" if with_token => token = Some(v.into_owned()),
+                _ => {
+                    return Err(fail(ApiError::UnknownArgument(if with_token {
+                        &["code", "token"]
+                    } else {
+                        &["code"]
+                    })));
                 }
-                _ => {}
             }
         }
-    }
-
-    let length = if let Some(l) = length {
-        l
-    } else {
-        return Err(status_response!(
-            StatusCode::BAD_REQUEST,
-            TYPE_TEXT,
-            "Expected argument \"length\""
-        ));
     };
-    let length = if let Ok(l) = u64::from_str_radix(&length, 10) {
-        l
-    } else {
-        return Err(status_response!(
-            StatusCode::BAD_REQUEST,
-            TYPE_TEXT,
-            "\"length\" should be a decimal integer"
-        ));
+
+    let nameplate = match code.map(|c| c.parse::<u32>()) {
+        Some(Ok(n)) => n,
+        Some(Err(_)) => {
+            return Err(fail(ApiError::InvalidArgument(
+                "code",
+                "only the number in front of the words",
+            )));
+        }
+        None => {
+            return Err(fail(ApiError::MissingArgument("code")));
+        }
     };
-    if length > CONFIG.max_content_length {
-        return Err(status_response!(
-            StatusCode::BAD_REQUEST,
-            TYPE_TEXT,
-            "Content is too long"
-        ));
+    match token {
+        Some(token) => Ok((nameplate, token)),
+        None if !with_token => Ok((nameplate, String::new())),
+        None => Err(fail(ApiError::MissingArgument("token"))),
     }
+}
 
-    Ok(length)
+// Opens a mailbox, answering with its nameplate and the opener's token, as "nameplate,token".
+// The opener makes up the words of the code itself.
+fn service_pair_open(in_flight: &InFlightMap) -> BoxFutRes {
+    in_flight.check_draining()?;
+
+    let token = generate_token();
+    let expiration = Instant::now() + Duration::from_secs(config().pairing_timeout_secs);
+    match in_flight.pairings.open(token.clone(), expiration) {
+        Some(nameplate) => {
+            in_flight
+                .expiry
+                .schedule(Deadline::Unpair(nameplate), expiration);
+            Ok(std_response!(TYPE_TEXT, format!("{},{}", nameplate, token)))
+        }
+        None => Err(fail(ApiError::CodesExhausted)),
+    }
 }
 
-fn service_request_id(uri: &Uri, in_flight: &InFlightMap) -> BoxFutRes {
-    let length = query_length(uri, true)?;
+// Takes the other place in a mailbox, answering with the joiner's token.
+fn service_pair_join(uri: &Uri, in_flight: &InFlightMap) -> BoxFutRes {
+    in_flight.check_draining()?;
+    let (nameplate, _) = query_pairing(uri, false)?;
 
-    loop {
-        let (id, secret) = generate_id_pair();
+    let token = generate_token();
+    match in_flight.pairings.join(nameplate, token.clone()) {
+        Ok(()) => Ok(std_response!(TYPE_TEXT, token)),
+        Err(e) => Err(fail(e.into())),
+    }
+}
 
-        let combo = id.clone() + "," + &secret;
-        match in_flight.lock().unwrap().entry(id) {
-            Entry::Occupied(_) => {
-                continue;
-            }
-            Entry::Vacant(entry) => {
-                entry.insert(Paste {
-                    secret,
-                    length,
-                    expiration: Instant::now() + Duration::from_secs(CONFIG.timeout_secs),
-                    uploaders: VecDeque::new(),
-                });
-                return Ok(std_response!(TYPE_TEXT, combo));
-            }
+// Passes the body on to the other side as it is.
+fn service_pair_send(req: Request<Body>, in_flight: &InFlightMap) -> BoxFutRes {
+    let (nameplate, token) = query_pairing(req.uri(), true)?;
+
+    match req.body().content_length() {
+        Some(length) if length <= pairing::MAX_MESSAGE_LENGTH => {}
+        Some(_) => {
+            return Err(fail(ApiError::MessageTooLong));
+        }
+        None => {
+            return Err(fail(ApiError::LengthRequired));
         }
     }
+
+    let in_flight = in_flight.clone();
+    Ok(Box::new(req.into_body().concat2().and_then(
+        move |body| {
+            match in_flight
+                .pairings
+                .send(nameplate, &token, body.into_bytes())
+            {
+                Ok(()) => std_response!(TYPE_TEXT, "Sent"),
+                Err(e) => fail(e.into()),
+            }
+        },
+    )))
 }
 
-fn service_retire_id(uri: &Uri, in_flight: &InFlightMap) -> BoxFutRes {
-    let (id, secret) = query_id_and_secret(uri, true)?;
+// Long-polled for the next message from the other side, answered with 204 if none came in time.
+fn service_pair_receive(uri: &Uri, in_flight: &InFlightMap) -> BoxFutRes {
+    let (nameplate, token) = query_pairing(uri, true)?;
 
-    match in_flight.lock().unwrap().entry(id) {
-        Entry::Occupied(mut entry) => {
-            {
-                let paste = entry.get_mut();
+    let message = match in_flight.pairings.receive(nameplate, &token) {
+        Ok(Received::Message(message)) => {
+            return Ok(std_response!("application/octet-stream", message));
+        }
+        Ok(Received::Wait(message)) => message,
+        Err(e) => return Err(fail(e.into())),
+    };
 
-                if paste.secret != secret {
-                    return Err(status_response!(
-                        StatusCode::FORBIDDEN,
-                        TYPE_TEXT,
-                        "Bad secret"
-                    ));
-                }
+    let in_flight = in_flight.clone();
+    let timeout = Delay::new(Duration::from_secs(config().wait_timeout_secs));
+    Ok(Box::new(message.select2(timeout).then(move |result| {
+        match result {
+            Ok(future::Either::A((message, _))) => {
+                std_response!("application/octet-stream", message)
             }
-            entry.remove_entry();
-            return Ok(std_response!(TYPE_TEXT, "Removed"));
-        }
-        Entry::Vacant(_) => {
-            return Err(status_response!(
-                StatusCode::NOT_FOUND,
-                TYPE_TEXT,
-                "Unknown id"
-            ));
+            Ok(future::Either::B(_)) | Err(future::Either::B(_)) => {
+                status_response!(StatusCode::NO_CONTENT, TYPE_TEXT, "")
+            }
+            // closed by the other side, expired, or the server is shutting down
+            Err(future::Either::A(_)) => match in_flight.check_draining() {
+                Err(response) => response,
+                Ok(()) => fail(ApiError::UnknownCode),
+            },
         }
-    };
+    })))
 }
 
-fn service_upload(req: Request<Body>, in_flight: &InFlightMap) -> BoxFutRes {
-    let (header, body) = req.into_parts();
+fn service_pair_close(uri: &Uri, in_flight: &InFlightMap) -> BoxFutRes {
+    let (nameplate, token) = query_pairing(uri, true)?;
 
-    let (id, secret) = query_id_and_secret(&header.uri, true)?;
+    match in_flight.pairings.close(nameplate, &token) {
+        Ok(()) => Ok(std_response!(TYPE_TEXT, "Closed")),
+        Err(e) => Err(fail(e.into())),
+    }
+}
 
-    let length = if let Some(length) = body.content_length() {
-        if length > CONFIG.max_content_length {
-            return Err(status_response!(
-                StatusCode::PAYLOAD_TOO_LARGE,
-                TYPE_TEXT,
-                "Content is too long"
-            ));
-        }
-        length
+// Server-Sent Events for the uploader about what happens to its paste: downloaders arriving,
+// transfers completing or aborting, and the paste nearing expiry or being retired.
+fn service_events(uri: &Uri, in_flight: &InFlightMap) -> BoxFutRes {
+    let (id, secret) = query_id_and_secret(uri, true)?;
+
+    match in_flight.shard(&id).get(&id) {
+        Some(paste) if !check_secret(paste, &secret) => Err(fail(ApiError::BadSecret)),
+        Some(paste) => Ok(Box::new(future::ok(
+            Response::builder()
+                .header(header::CONTENT_TYPE, TYPE_EVENTS)
+                .header(header::CACHE_CONTROL, "no-cache")
+                .body(Bod(paste.events.subscribe()))
+                .unwrap(),
+        ))),
+        None => Err(fail(ApiError::UnknownId)),
+    }
+}
+
+fn service_socket(req: Request<Body>, in_flight: &InFlightMap) -> BoxFutRes {
+    in_flight.check_draining()?;
+
+    let (id, secret) = query_id_and_secret(req.uri(), true)?;
+
+    let accept = if let Some(accept) = socket::accept_key(req.headers()) {
+        accept
     } else {
-        return Err(status_response!(
-            StatusCode::LENGTH_REQUIRED,
-            TYPE_TEXT,
-            "Content-Length must be speicfied"
-        ));
+        return Err(fail(ApiError::ExpectedWebSocket));
     };
 
-    let (complete, completion) = sync::oneshot::channel();
+    let (requests, incoming) = sync::mpsc::channel(socket::MAX_PENDING_REQUESTS);
 
-    match in_flight.lock().unwrap().entry(id) {
+    match in_flight.shard(&id).entry(id) {
         Entry::Occupied(mut entry) => {
             let paste = entry.get_mut();
-            if paste.secret != secret {
-                return Err(status_response!(
-                    StatusCode::FORBIDDEN,
-                    TYPE_TEXT,
-                    "Bad secret"
-                ));
+            if !check_secret(paste, &secret) {
+                return Err(fail(ApiError::BadSecret));
             }
-            if paste.length != length {
-                return Err(status_response!(
-                    StatusCode::BAD_REQUEST,
-                    TYPE_TEXT,
-                    "Wrong length"
-                ));
+            if !paste.files.is_empty() {
+                return Err(fail(ApiError::BundleUploadOnly));
             }
 
-            // TODO not sure if we really want someone to be able to
-            // queue up many uploads, actually...
-            // Probably needs at least an upper limit.
-            paste.uploaders.push_back(Forwarder {
-                length: paste.length,
-                bytes_sent: 0,
-                uploader: Some((body, complete)),
-            });
+            // replacing an older socket closes it
+            paste.socket = Some(requests);
         }
         Entry::Vacant(_) => {
-            return Err(status_response!(
-                StatusCode::NOT_FOUND,
-                TYPE_TEXT,
-                "Unknown id"
-            ));
+            return Err(fail(ApiError::UnknownId));
         }
     };
 
-    // TODO technically we'd want this to be an Err when this fails somehow
-    Ok(Box::new(
-        completion
-            .or_else(|_| {
-                future::ok(
-                    Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .header(header::CONTENT_TYPE, TYPE_TEXT)
-                        .body(Bod(Body::from("BAD NEWS")))
-                        .unwrap(),
-                )
-            })
-            .map_err(|_: sync::oneshot::Canceled| unreachable!()),
-    ))
+    hyper::rt::spawn(
+        req.into_body()
+            .on_upgrade()
+            .map_err(|e| eprintln!("upgrade error: {}", e))
+            .and_then(move |upgraded| socket::Session::new(upgraded, incoming)),
+    );
+
+    Ok(Box::new(future::ok(
+        Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(header::UPGRADE, "websocket")
+            .header(header::CONNECTION, "Upgrade")
+            .header(header::SEC_WEBSOCKET_ACCEPT, accept)
+            .body(Bod(Body::empty()))
+            .unwrap(),
+    )))
 }
 
-fn service_download(uri: &Uri, in_flight: &InFlightMap) -> BoxFutRes {
-    let id = query_id(uri, true)?;
+fn service_not_found() -> BoxFutRes {
+    Err(fail(ApiError::NotFound))
+}
 
-    // TODO refactor for less ugliness
-    fn download(id: String, mut retries: u64, in_flight: InFlightMap) -> BoxFut {
-        match in_flight.lock().unwrap().entry(id.clone()) {
-            Entry::Occupied(mut entry) => {
-                let paste = entry.get_mut();
-                match paste.uploaders.pop_front() {
-                    Some(forwarder) => {
-                        if forwarder.uploader.is_some()
-                            && !forwarder.uploader.as_ref().unwrap().1.is_canceled()
-                        {
-                            paste.expiration =
-                                Instant::now() + Duration::from_secs(CONFIG.timeout_secs);
-                            return Box::new(future::ok(
-                                Response::builder()
-                                    .header(header::CONTENT_TYPE, TYPE_TEXT)
-                                    .body(Fwd(forwarder))
-                                    .unwrap(),
-                            ));
-                        } else {
-                            // fall through to retry
-                        }
-                    }
-                    None => {
-                        // fall through to retry
-                    }
-                }
-            }
-            Entry::Vacant(_) => {
-                return status_response!(StatusCode::NOT_FOUND, TYPE_HTML, "<b>Unknown id</b>");
-            }
-        };
+// Counters in the Prometheus text format.
+fn service_metrics(in_flight: &InFlightMap) -> BoxFutRes {
+    let active = in_flight.transfers.lock().unwrap().len();
+    let metrics = format!(
+        "# HELP rendezvous_transfers_active Transfers being forwarded to a downloader.\n\
+         # TYPE rendezvous_transfers_active gauge\n\
+         rendezvous_transfers_active {}\n\
+         # HELP rendezvous_transfers_slow Transfers reading below the minimum throughput.\n\
+         # TYPE rendezvous_transfers_slow gauge\n\
+         rendezvous_transfers_slow {}\n\
+         # HELP rendezvous_transfers_stalled_total Transfers aborted for a stalled downloader.\n\
+         # TYPE rendezvous_transfers_stalled_total counter\n\
+         rendezvous_transfers_stalled_total {}\n\
+         # HELP rendezvous_cache_bytes Paste content kept in the cache.\n\
+         # TYPE rendezvous_cache_bytes gauge\n\
+         rendezvous_cache_bytes {}\n\
+         # HELP rendezvous_pairings_active Open pairing mailboxes.\n\
+         # TYPE rendezvous_pairings_active gauge\n\
+         rendezvous_pairings_active {}\n",
+        active,
+        in_flight.slow_transfers.load(Ordering::Relaxed),
+        in_flight.stalled_transfers.load(Ordering::Relaxed),
+        in_flight.cache.size(),
+        in_flight.pairings.len(),
+    );
+    Ok(std_response!("text/plain; version=0.0.4", metrics))
+}
 
-        Box::new(
-            Delay::new(Duration::from_millis(CONFIG.download_retry_ms))
-            .or_else(|_| future::ok(())) // TODO probably should not retry on timer errors?
-            .and_then(move |_| {
-                if retries > 0 {
-                    retries -= 1;
-                    download(id, retries, in_flight)
-                } else {
-                    status_response!(
-                            StatusCode::SERVICE_UNAVAILABLE,
-                            TYPE_HTML,
-                            "<b>No uploader currently available</b>"
-                            )
-                }
+fn service_dump(_in_flight: &InFlightMap) -> BoxFutRes {
+    #[cfg(debug_assertions)]
+    for shard in &_in_flight.shards {
+        println!("{:?}", shard);
+    }
+    service_not_found()
+}
+
+fn service(in_flight: InFlightMap, cluster: Arc<Cluster>) -> impl Fn(Request<Body>) -> BoxFut {
+    move |mut req| {
+        let mounted = strip_base_path(&mut req);
+
+        // the v2 API always answers in JSON
+        let path = req.uri().path();
+        let flavor = if path == "/2" || path.starts_with("/2/") {
+            Flavor::Json
+        } else {
+            Flavor::accepted(req.headers())
+        };
+        let origin = cors::allowed_origin(req.headers());
+        // paths outside base_path are someone else's to answer
+        if let (true, Some(origin)) = (mounted, &origin) {
+            if let Some(response) = cors::preflight(req.method(), req.headers(), origin) {
+                return response;
             }
-            ),
-        )
-    };
+        }
 
-    Ok(download(id, CONFIG.download_max_retries, in_flight.clone()))
+        let response = if mounted {
+            route(req, &in_flight, &cluster)
+        } else {
+            fail(ApiError::NotFound)
+        };
+        Box::new(response.map(move |response| {
+            let response = error::render(response, flavor);
+            match origin {
+                Some(origin) => cors::allow(response, origin),
+                None => response,
+            }
+        }))
+    }
 }
 
-fn service_not_found() -> BoxFutRes {
-    let mut response = Response::builder();
+fn route(req: Request<Body>, in_flight: &InFlightMap, cluster: &Cluster) -> BoxFut {
+    // pastes held by another node are handled there
+    if let Some(target) = cluster.route(&req) {
+        return cluster.forward(req, target);
+    }
 
-    response.header(header::CONTENT_TYPE, HeaderValue::from_static(TYPE_HTML));
-    response.status(StatusCode::NOT_FOUND);
+    let result = match (req.method(), req.uri().path()) {
+        // user-facing
+        (&Method::GET, "/") => service_home(req.headers()),
+        (&Method::GET, path @ "/favicon.ico")
+        | (&Method::GET, path @ "/client.js")
+        | (&Method::GET, path @ "/landing.js")
+        | (&Method::GET, path @ "/pair.js")
+        | (&Method::GET, path @ "/words.txt") => service_asset(path, req.headers()),
+        (&Method::GET, path) if path.starts_with("/static/") => service_asset(path, req.headers()),
+        (&Method::GET, path) if path.starts_with("/p/") => service_landing(path, in_flight),
+        (&Method::GET, "/receive") => service_receive(),
+
+        // API v1
+        (&Method::POST, "/1/id/request") => {
+            service_request_id(req.uri(), req.headers(), in_flight, cluster)
+        }
+        (&Method::POST, "/1/id/retire") => service_retire_id(req.uri(), in_flight),
+        (&Method::GET, "/1/id/events") => service_events(req.uri(), in_flight),
+        (&Method::POST, "/1/file/upload") => service_upload(req, in_flight),
+        (&Method::GET, "/1/file/wait") => service_wait(req.uri(), in_flight),
+        (&Method::GET, "/1/file/download") => service_download(req.uri(), req.headers(), in_flight),
+        (&Method::GET, "/1/file/socket") => service_socket(req, in_flight),
+        (&Method::GET, "/1/file/index") => service_index(req.uri(), in_flight),
+        (&Method::POST, "/1/pair/open") => service_pair_open(in_flight),
+        (&Method::POST, "/1/pair/join") => service_pair_join(req.uri(), in_flight),
+        (&Method::POST, "/1/pair/send") => service_pair_send(req, in_flight),
+        (&Method::GET, "/1/pair/receive") => service_pair_receive(req.uri(), in_flight),
+        (&Method::POST, "/1/pair/close") => service_pair_close(req.uri(), in_flight),
+
+        // API v2
+        (_, path) if path == "/2" || path.starts_with("/2/") => {
+            return api_v2::service(req, in_flight, cluster);
+        }
 
-    Err(Box::new(future::ok(
-        response
-            .body(Bod(Body::from(r"<b>404 Not Found</b>")))
-            .unwrap(),
-    )))
+        // debug
+        (&Method::GET, "/dump") => service_dump(in_flight),
+        (&Method::GET, "/metrics") => service_metrics(in_flight),
+
+        // everything else
+        _ => service_not_found(),
+    };
+
+    match result {
+        Ok(r) => r,
+        Err(r) => r,
+    }
 }
 
-fn service_dump(_in_flight: &InFlightMap) -> BoxFutRes {
-    #[cfg(debug_assertions)]
-    println!("{:?}", _in_flight);
-    service_not_found()
+fn schedule_timeout(in_flight: InFlightMap) {
+    hyper::rt::spawn(
+        ExpiryQueue::expired(&in_flight.expiry).for_each(move |deadline| {
+            process_timeout(&in_flight, deadline);
+            Ok(())
+        }),
+    );
 }
 
-fn service(in_flight: InFlightMap) -> impl Fn(Request<Body>) -> BoxFut {
-    move |req| {
-        let result = match (req.method(), req.uri().path()) {
-            // user-facing
-            (&Method::GET, "/") => service_home(),
-            (&Method::GET, "/favicon.ico") => service_favicon(),
-            (&Method::GET, "/client.js") => service_js(),
+fn process_timeout(in_flight: &InFlightMap, deadline: Deadline) {
+    let now = Instant::now();
+
+    // the deadline may be stale if the paste was refreshed since it was scheduled
+    match deadline {
+        Deadline::Warn(id) => {
+            if let Some(paste) = in_flight.shard(&id).get(&id) {
+                let warning = Duration::from_secs(config().expiry_warning_secs);
+                if paste.expiration > now && paste.expiration <= now + warning {
+                    paste.events.send(
+                        "expiring",
+                        &format!(r#"{{"seconds":{}}}"#, (paste.expiration - now).as_secs()),
+                    );
+                }
+            }
+        }
+        Deadline::Expire(id) => {
+            if let Entry::Occupied(entry) = in_flight.shard(&id).entry(id) {
+                if entry.get().expiration <= now {
+                    let (id, paste) = entry.remove_entry();
+                    in_flight.cache.remove(&id);
+                    paste.events.send("retired", r#"{"reason":"expired"}"#);
+                    paste.events.close();
+                    in_flight.mark_dirty();
+                }
+            }
+        }
+        Deadline::Unpair(nameplate) => in_flight.pairings.expire(nameplate, now),
+    }
+}
 
-            // API v1
-            (&Method::POST, "/1/id/request") => service_request_id(req.uri(), &in_flight),
-            (&Method::POST, "/1/id/retire") => service_retire_id(req.uri(), &in_flight),
-            (&Method::POST, "/1/file/upload") => service_upload(req, &in_flight),
-            (&Method::GET, "/1/file/download") => service_download(req.uri(), &in_flight),
+// Once a second, abort transfers whose downloader has stalled, so they stop pinning the uploader.
+fn schedule_watchdog(in_flight: InFlightMap) {
+    hyper::rt::spawn(
+        Interval::new(Duration::from_secs(1))
+            .map_err(|e| eprintln!("watchdog timer error: {}", e))
+            .for_each(move |_| {
+                check_transfers(&in_flight);
+                Ok(())
+            }),
+    );
+}
 
-            // debug
-            (&Method::GET, "/dump") => service_dump(&in_flight),
+fn check_transfers(in_flight: &InFlight) {
+    let now = Instant::now();
+    let mut slow = 0;
 
-            // everything else
-            _ => service_not_found(),
+    // transfers that have been dropped by hyper are done with, one way or another
+    in_flight.transfers.lock().unwrap().retain(|transfer| {
+        let transfer = match transfer.upgrade() {
+            Some(transfer) => transfer,
+            None => return false,
         };
+        let mut forwarder = transfer.lock().unwrap();
+        if forwarder.uploader.is_none() {
+            return false;
+        }
 
-        match result {
-            Ok(r) => r,
-            Err(r) => r,
+        if forwarder.is_stalled(now) {
+            forwarder.abort_stalled();
+            in_flight.stalled_transfers.fetch_add(1, Ordering::Relaxed);
+            return false;
         }
-    }
+        if forwarder.is_slow(now) {
+            slow += 1;
+        }
+        true
+    });
+
+    in_flight.slow_transfers.store(slow, Ordering::Relaxed);
 }
 
-fn schedule_timeout(in_flight: InFlightMap) {
+// Saving is done on a thread that may block, so the file being slow to write doesn't hold up
+// requests.
+fn schedule_save(in_flight: InFlightMap, path: String) {
     hyper::rt::spawn(
-        Delay::new(Duration::from_secs(CONFIG.timeout_scan_interval_secs))
-            .or_else(|_| future::ok(()))
-            .and_then(move |_| {
-                process_timeout(&in_flight);
-                schedule_timeout(in_flight);
-                future::ok(())
+        Interval::new(Duration::from_secs(config().state_save_interval_secs))
+            .map_err(|e| eprintln!("state save timer error: {}", e))
+            .for_each(move |_| {
+                let in_flight = in_flight.clone();
+                let path = path.clone();
+                future::poll_fn(move || {
+                    tokio_threadpool::blocking(|| {
+                        if in_flight.dirty.swap(false, Ordering::SeqCst) {
+                            save_state(&in_flight, &path);
+                        }
+                    })
+                })
+                .or_else(|e| {
+                    eprintln!("state save error: {}", e);
+                    Ok(())
+                })
             }),
     );
 }
 
-fn process_timeout(in_flight: &InFlightMap) {
+fn save_state(in_flight: &InFlightMap, path: &str) {
+    let now = Instant::now();
+    let wall_now = SystemTime::now();
+
+    let mut saved = Vec::new();
+    for shard in &in_flight.shards {
+        for (id, paste) in shard.lock().unwrap().iter() {
+            if paste.expiration <= now {
+                continue;
+            }
+            let expires = (wall_now + (paste.expiration - now))
+                .duration_since(UNIX_EPOCH)
+                .unwrap();
+            saved.push(state::SavedPaste {
+                id: id.clone(),
+                secret_hmac: Some(state::to_hex(&paste.secret_hash)),
+                secret_sha256: None,
+                length: paste.length,
+                cache: paste.cache,
+                encoding: paste.encoding.map(|e| e.name().to_owned()),
+                timeout_secs: Some(paste.timeout.as_secs()),
+                files: paste
+                    .files
+                    .iter()
+                    .map(|f| state::SavedFile {
+                        name: f.entry.name.clone(),
+                        length: f.entry.length,
+                    })
+                    .collect(),
+                // round up so a restored paste never expires early
+                expires: expires.as_secs() + 1,
+            });
+        }
+    }
+
+    if let Err(e) = state::save(path, saved) {
+        eprintln!("Error writing state file: {}", e);
+        in_flight.mark_dirty();
+    }
+}
+
+fn restore_state(in_flight: &InFlightMap, path: &str) {
+    let saved = match state::load(path) {
+        Ok(saved) => saved,
+        Err(e) => {
+            eprintln!("Error reading state file: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let config = config();
     let now = Instant::now();
+    let unix_now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    for paste in saved {
+        if paste.expires <= unix_now {
+            continue;
+        }
+
+        let mut secret_hash = [0; 32];
+        match (&paste.secret_hmac, &paste.secret_sha256) {
+            (Some(_), _) if SECRET_KEY.fresh => {
+                eprintln!("Skipping saved id {}, its key file is gone", paste.id);
+                continue;
+            }
+            (Some(hex), _) if state::from_hex(hex, &mut secret_hash) => {}
+            (None, Some(hex)) if state::from_hex(hex, &mut secret_hash) => {
+                secret_hash = keyed_hash(&secret_hash);
+            }
+            _ => {
+                eprintln!("Skipping saved id {}, bad secret hash", paste.id);
+                continue;
+            }
+        }
+
+        let encoding = match paste.encoding {
+            None => None,
+            Some(ref name) => match Encoding::parse(name) {
+                Some(e) => Some(e),
+                None => {
+                    eprintln!("Skipping saved id {}, unknown encoding {}", paste.id, name);
+                    continue;
+                }
+            },
+        };
+
+        let expiration = now + Duration::from_secs(paste.expires - unix_now);
+        in_flight.schedule_expiry(&paste.id, expiration);
+        in_flight.shard(&paste.id).insert(
+            paste.id,
+            Paste {
+                secret_hash,
+                length: paste.length,
+                cache: paste.cache,
+                encoding,
+                expiration,
+                timeout: Duration::from_secs(paste.timeout_secs.unwrap_or(config.timeout_secs)),
+                uploaders: VecDeque::new(),
+                socket: None,
+                waiters: VecDeque::new(),
+                transfers: HashMap::new(),
+                broadcast: None,
+                events: Events::default(),
+                files: paste
+                    .files
+                    .into_iter()
+                    .map(|f| BundleFile {
+                        entry: bundle::Entry {
+                            name: f.name,
+                            length: f.length,
+                        },
+                        uploaders: VecDeque::new(),
+                    })
+                    .collect(),
+            },
+        );
+    }
+}
+
+// Resolves on the first SIGINT or SIGTERM.
+fn shutdown_signal() -> Box<dyn Future<Item = (), Error = ()> + Send> {
+    let signals = tokio_signal::ctrl_c().flatten_stream();
+
+    #[cfg(unix)]
+    let signals = {
+        use tokio_signal::unix::{Signal, SIGTERM};
+        signals.select(Signal::new(SIGTERM).flatten_stream().map(|_| ()))
+    };
+
+    Box::new(
+        signals
+            .into_future()
+            .map(|_| ())
+            .map_err(|(e, _)| eprintln!("signal error: {}", e)),
+    )
+}
+
+// Yields on every SIGHUP.
+fn reload_signal() -> Box<dyn Stream<Item = (), Error = ()> + Send> {
+    #[cfg(unix)]
+    {
+        use tokio_signal::unix::{Signal, SIGHUP};
+        Box::new(
+            Signal::new(SIGHUP)
+                .flatten_stream()
+                .map(|_| ())
+                .map_err(|e| eprintln!("signal error: {}", e)),
+        )
+    }
 
-    // TODO some way of reporting time left to client
-    in_flight.lock().unwrap().retain(|_, v| v.expiration > now);
+    #[cfg(not(unix))]
+    Box::new(futures::stream::empty())
 }
 
 fn main() {
-    lazy_static::initialize(&CONFIG);
+    let args = &*config::ARGS;
+    if args.help {
+        print!("{}", config::USAGE);
+        return;
+    }
+    if args.version {
+        println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        return;
+    }
+    if args.print_default_config {
+        print!("{}", config::DEFAULTS);
+        return;
+    }
+
+    // Settings only read here can't be changed by a reload.
+    let startup = config();
+    if args.check_config {
+        println!("Configuration OK");
+        return;
+    }
+
+    let addr = startup.bind.parse().unwrap();
 
-    let addr = CONFIG.bind.parse().unwrap();
+    let in_flight = Arc::new(InFlight::new());
 
-    let in_flight = Arc::new(Mutex::new(HashMap::new()));
+    lazy_static::initialize(&SECRET_KEY);
+    if let Some(ref path) = startup.state_file {
+        restore_state(&in_flight, path);
+    }
+
+    let (drain_start, drain_started) = sync::oneshot::channel::<Instant>();
+    let drain_started = drain_started.shared();
+
+    let cluster = Arc::new(Cluster::new(
+        startup.node_id.clone(),
+        startup.nodes.clone(),
+        startup.cluster_redirect,
+    ));
 
     let server_clone = in_flight.clone();
-    let http_server = Server::bind(&addr)
-        .serve(move || service_fn(service(server_clone.clone())))
-        .map_err(|e| eprintln!("server error: {}", e));
+    let new_service = move || service_fn(service(server_clone.clone(), cluster.clone()));
+    let graceful = drain_started
+        .clone()
+        .map(|_| ())
+        .or_else(|_| future::empty::<(), ()>());
+
+    let incoming = AddrIncoming::bind(&addr).unwrap_or_else(|e| {
+        eprintln!("Error binding {}: {}", addr, e);
+        process::exit(1);
+    });
+
+    // HTTP/2 is picked up from the connection preface, so without TLS clients can use it with
+    // prior knowledge (h2c).
+    let http_server: Box<dyn Future<Item = (), Error = ()> + Send> =
+        match (&startup.tls_cert_file, &startup.tls_key_file) {
+            (Some(cert), Some(key)) => {
+                let incoming = tls::acceptor(cert, key, startup.http2)
+                    .map(|acceptor| tls::incoming(incoming, acceptor))
+                    .unwrap_or_else(|e| {
+                        eprintln!("Error setting up TLS: {}", e);
+                        process::exit(1);
+                    });
+                Box::new(
+                    Server::builder(incoming)
+                        .http1_only(!startup.http2)
+                        .serve(new_service)
+                        .with_graceful_shutdown(graceful)
+                        .map_err(|e| eprintln!("server error: {}", e)),
+                )
+            }
+            (None, None) => Box::new(
+                Server::builder(incoming)
+                    .http1_only(!startup.http2)
+                    .serve(new_service)
+                    .with_graceful_shutdown(graceful)
+                    .map_err(|e| eprintln!("server error: {}", e)),
+            ),
+            _ => unreachable!("tls_cert_file and tls_key_file are checked to be set together"),
+        };
+
+    // Transfers still going after the deadline are dropped along with the runtime.
+    let drain_deadline = drain_started
+        .map_err(|_| ())
+        .and_then(|deadline| Delay::new_at(*deadline).map_err(|_| ()))
+        .or_else(|_| future::empty());
 
     let timeout_clone = in_flight.clone();
-    let timeout_kickoff = future::lazy(move || future::ok(schedule_timeout(timeout_clone.clone())));
+    let timeout_kickoff = future::lazy(move || {
+        schedule_timeout(timeout_clone.clone());
+        future::ok(())
+    });
+
+    let watchdog_clone = in_flight.clone();
+    let watchdog_kickoff = future::lazy(move || {
+        schedule_watchdog(watchdog_clone.clone());
+        future::ok(())
+    });
+
+    let save_clone = in_flight.clone();
+    let save_kickoff = future::lazy(move || {
+        if let Some(ref path) = config().state_file {
+            schedule_save(save_clone.clone(), path.clone());
+        }
+        future::ok(())
+    });
 
-    let server = Future::join(http_server, timeout_kickoff).map(|_| ());
+    let shutdown_clone = in_flight.clone();
+    let shutdown_kickoff = future::lazy(move || {
+        hyper::rt::spawn(shutdown_signal().map(move |()| {
+            let drain_secs = config().shutdown_drain_secs;
+            eprintln!(
+                "Shutting down, waiting up to {} seconds for transfers to finish",
+                drain_secs
+            );
+            let deadline = Instant::now() + Duration::from_secs(drain_secs);
+            shutdown_clone.begin_drain(deadline);
+            let _ = drain_start.send(deadline);
+        }));
+        future::ok(())
+    });
+
+    let reload_kickoff = future::lazy(|| {
+        hyper::rt::spawn(reload_signal().for_each(|()| {
+            reload_config();
+            Ok(())
+        }));
+        future::ok(())
+    });
+
+    let server = http_server
+        .select(drain_deadline)
+        .map_err(|_| ())
+        .join5(
+            timeout_kickoff,
+            watchdog_kickoff,
+            save_kickoff,
+            shutdown_kickoff,
+        )
+        .join(reload_kickoff)
+        .map(|_| ());
+
+    let mut runtime = Runtime::new().unwrap();
+    let _ = runtime.block_on(server);
+    runtime.shutdown_now().wait().unwrap();
+
+    if let Some(ref path) = config().state_file {
+        save_state(&in_flight, path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::sync::oneshot;
+    use futures::{stream, Future, Stream};
+    use hyper::header;
+    use hyper::{Body, Client, Request, StatusCode, Version};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use tokio::runtime::Runtime;
+
+    use cluster::Cluster;
+    use testing;
+
+    #[test]
+    fn h2_uploads_are_held_back_by_a_slow_downloader() {
+        // as large as max_content_length allows
+        const CHUNK: usize = 16 * 1024;
+        const CHUNKS: usize = 64;
+        const LENGTH: usize = CHUNK * CHUNKS;
+
+        let mut runtime = Runtime::new().unwrap();
+        let (listener, addr) = testing::listen();
+        testing::serve(
+            &mut runtime,
+            listener,
+            Cluster::new(None, HashMap::new(), false),
+        );
+        // both sides share one connection
+        let client = Client::builder().http2_only(true).build_http();
+
+        let create = Request::post(format!("http://{}/2/pastes?length={}", addr, LENGTH))
+            .body(Body::empty())
+            .unwrap();
+        let (status, created) = runtime.block_on(testing::fetch(&client, create)).unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        let id = testing::json_field(&created, "id");
+        let secret = testing::json_field(&created, "secret");
+
+        // how much of the upload the server has asked for
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let counter = pulled.clone();
+        let content = stream::iter_ok::<_, hyper::Error>(0..CHUNKS).map(move |_| {
+            counter.fetch_add(CHUNK, Ordering::SeqCst);
+            vec![b'x'; CHUNK]
+        });
+        let upload = Request::put(format!("http://{}/2/pastes/{}", addr, id))
+            .header(header::AUTHORIZATION, format!("Bearer {}", secret))
+            .header(header::CONTENT_LENGTH, LENGTH)
+            .body(Body::wrap_stream(content))
+            .unwrap();
+        let upload = oneshot::spawn(testing::fetch(&client, upload), &runtime.executor());
+
+        let download = Request::get(format!("http://{}/2/pastes/{}", addr, id))
+            .body(Body::empty())
+            .unwrap();
+        let response = runtime.block_on(client.request(download)).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.version(), Version::HTTP_2);
+        let (first, rest) = runtime
+            .block_on(response.into_body().into_future().map_err(|(e, _)| e))
+            .unwrap();
+        let first = first.unwrap().len();
+
+        // the downloader stops reading, so the upload should stall at about the flow control
+        // windows rather than be read in full
+        thread::sleep(Duration::from_millis(500));
+        let held = pulled.load(Ordering::SeqCst);
+        assert!(held < LENGTH / 4, "{} of {} bytes pulled", held, LENGTH);
+
+        let rest = runtime.block_on(rest.concat2()).unwrap().len();
+        assert_eq!(first + rest, LENGTH);
+        let (status, _) = runtime.block_on(upload).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(pulled.load(Ordering::SeqCst), LENGTH);
+    }
+
+    #[test]
+    fn bundles_can_hold_empty_files() {
+        let mut runtime = Runtime::new().unwrap();
+        let (listener, addr) = testing::listen();
+        testing::serve(
+            &mut runtime,
+            listener,
+            Cluster::new(None, HashMap::new(), false),
+        );
+        let client = Client::new();
+
+        let create = Request::post(format!(
+            "http://{}/2/pastes?file=a.txt:5&file=dir/b.txt:0",
+            addr
+        ))
+        .body(Body::empty())
+        .unwrap();
+        let (status, created) = runtime.block_on(testing::fetch(&client, create)).unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        let id = testing::json_field(&created, "id");
+        let secret = testing::json_field(&created, "secret");
+
+        let upload = |file: &str, content: &'static str| {
+            let upload = Request::post(format!(
+                "http://{}/1/file/upload?id={}&secret={}&file={}",
+                addr, id, secret, file
+            ))
+            .header(header::CONTENT_LENGTH, content.len())
+            .body(Body::from(content))
+            .unwrap();
+            testing::fetch(&client, upload)
+        };
+        let download = Request::get(format!(
+            "http://{}/1/file/download?id={}&format=tar",
+            addr, id
+        ))
+        .body(Body::empty())
+        .unwrap();
+        let ((a, b), (status, archive)) = runtime
+            .block_on(
+                upload("a.txt", "hello")
+                    .join(upload("dir/b.txt", ""))
+                    .join(testing::fetch(&client, download)),
+            )
+            .unwrap();
+
+        assert_eq!(a, (StatusCode::OK, "Sent!".to_owned()));
+        assert_eq!(b, (StatusCode::OK, "Sent!".to_owned()));
+        assert_eq!(status, StatusCode::OK);
+        // a header and a padded block for a.txt, a header alone for b.txt, and the end
+        assert_eq!(archive.len(), 512 * 5);
+        assert!(archive.starts_with("a.txt\0"));
+        assert_eq!(&archive[512..517], "hello");
+        assert!(archive[1024..].starts_with("dir/b.txt\0"));
+    }
 
-    hyper::rt::run(server);
+    #[test]
+    fn byte_ranges() {
+        use super::byte_range;
+
+        assert_eq!(byte_range("bytes=0-4", 10), Ok(Some((0, 5))));
+        assert_eq!(byte_range("bytes=5-", 10), Ok(Some((5, 5))));
+        assert_eq!(byte_range("bytes=-3", 10), Ok(Some((7, 3))));
+        // more than there is is cut short
+        assert_eq!(byte_range("bytes=8-20", 10), Ok(Some((8, 2))));
+        assert_eq!(byte_range("bytes=-20", 10), Ok(Some((0, 10))));
+
+        assert_eq!(byte_range("bytes=10-", 10), Err(()));
+        assert_eq!(byte_range("bytes=-0", 10), Err(()));
+        assert_eq!(byte_range("bytes=0-", 0), Err(()));
+
+        // anything else means the whole paste
+        for ignored in &[
+            "bytes=5-2",
+            "bytes=0-1,4-5",
+            "items=0-1",
+            "bytes=x-1",
+            "bytes=5",
+        ] {
+            assert_eq!(byte_range(ignored, 10), Ok(None), "{}", ignored);
+        }
+    }
 }
@@ -0,0 +1,318 @@
+'use strict';
+
+// Passing a paste to another device with a short code like "7-guitar-ocean" instead of its link.
+// The number is the nameplate of a mailbox on the server, and the words never leave the two
+// devices: they are the password for SPAKE2, run by swapping messages through the mailbox, which
+// leaves both sides with a key the server can't work out. The uploader then sends the paste's id
+// encrypted with that key. A wrong code gives the two sides different keys, so the id can't be
+// decrypted, and a mailbox only takes one receiver, so whoever guessed gets no second try.
+
+var pairBasePath = document.body.getAttribute('data-base-path') || '';
+
+// The 2048-bit MODP group of RFC 3526. 4, being a square, generates its subgroup of prime order
+// Q = (P - 1) / 2, which is where all the SPAKE2 values live.
+var P = BigInt('0x' +
+  'ffffffffffffffffc90fdaa22168c234c4c6628b80dc1cd129024e088a67cc74020bbea63b139b22514a08798e3404' +
+  'ddef9519b3cd3a431b302b0a6df25f14374fe1356d6d51c245e485b576625e7ec6f44c42e9a637ed6b0bff5cb6f406' +
+  'b7edee386bfb5a899fa5ae9f24117c4b1fe649286651ece45b3dc2007cb8a163bf0598da48361c55d39a69163fa8fd' +
+  '24cf5f83655d23dca3ad961c62f356208552bb9ed529077096966d670c354e4abc9804f1746c08ca18217c32905e46' +
+  '2e36ce3be39e772c180e86039b2783a2ec07a28fb5c55df06f4c52c9de2bcbf6955817183995497cea956ae515d226' +
+  '1898fa051015728e5a8aacaa68ffffffffffffffff');
+var Q = (P - 1n) / 2n;
+var G = 4n;
+
+var encoder = new TextEncoder();
+
+function modPow(base, exponent, modulus) {
+  var result = 1n;
+  base %= modulus;
+  while (exponent > 0n) {
+    if (exponent & 1n) {
+      result = result * base % modulus;
+    }
+    base = base * base % modulus;
+    exponent >>= 1n;
+  }
+  return result;
+}
+
+function toHex(bytes) {
+  return Array.prototype.map.call(new Uint8Array(bytes), function (b) {
+    return ('0' + b.toString(16)).slice(-2);
+  }).join('');
+}
+
+function fromHex(hex) {
+  var bytes = new Uint8Array(hex.length / 2);
+  for (var i = 0; i < bytes.length; i++) {
+    bytes[i] = parseInt(hex.substr(i * 2, 2), 16);
+  }
+  return bytes;
+}
+
+function sha256(text) {
+  return crypto.subtle.digest('SHA-256', encoder.encode(text));
+}
+
+function toBigInt(bytes) {
+  return BigInt('0x' + (toHex(bytes) || '0'));
+}
+
+// A number well past P bits, from nine SHA-256 hashes of the label, so its remainder is close to
+// uniform.
+function hashToNumber(label) {
+  var hashes = [];
+  for (var i = 0; i < 9; i++) {
+    hashes.push(sha256(label + ':' + i));
+  }
+  return Promise.all(hashes).then(function (hashes) {
+    return BigInt('0x' + hashes.map(toHex).join(''));
+  });
+}
+
+// M and N, the elements each side blinds its value with. Squaring puts them in the subgroup, and
+// nobody knows their discrete logs, as SPAKE2 needs.
+var blinding = Promise.all([
+  hashToNumber('rendezvous pairing M'),
+  hashToNumber('rendezvous pairing N')
+]).then(function (numbers) {
+  return numbers.map(function (n) {
+    return modPow(n % P, 2n, P);
+  });
+});
+
+function randomExponent() {
+  var bytes = new Uint8Array(288);
+  crypto.getRandomValues(bytes);
+  return toBigInt(bytes) % Q;
+}
+
+// The first half of SPAKE2 for the opener or the joiner, with the whole code as the password.
+// Resolves to the state the second half needs and the message to send to the other side.
+function spake2Start(opener, code) {
+  return Promise.all([blinding, sha256('rendezvous pairing password:' + code)])
+    .then(function (results) {
+      var own = results[0][opener ? 0 : 1];
+      var w = toBigInt(results[1]) % Q;
+      var x = randomExponent();
+      var message = modPow(G, x, P) * modPow(own, w, P) % P;
+      return {
+        state: { opener: opener, w: w, x: x, message: message, other: results[0][opener ? 1 : 0] },
+        message: message.toString(16)
+      };
+    });
+}
+
+// The second half, given the other side's message. Resolves to a key for AES-GCM.
+function spake2Finish(state, received) {
+  var theirs = BigInt('0x' + received);
+  // anything outside the subgroup could leak something about the password
+  if (theirs <= 1n || theirs >= P - 1n || modPow(theirs, Q, P) !== 1n) {
+    return Promise.reject(new Error('Bad key exchange message'));
+  }
+  var unblinded = theirs * modPow(modPow(state.other, state.w, P), P - 2n, P) % P;
+  var shared = modPow(unblinded, state.x, P);
+
+  var first = state.opener ? state.message : theirs;
+  var second = state.opener ? theirs : state.message;
+  var transcript = [first, second, shared, state.w].map(function (n) {
+    return n.toString(16);
+  }).join(':');
+  return sha256('rendezvous pairing key:' + transcript).then(function (key) {
+    return crypto.subtle.importKey('raw', key, 'AES-GCM', false, ['encrypt', 'decrypt']);
+  });
+}
+
+function encrypt(key, text) {
+  var iv = new Uint8Array(12);
+  crypto.getRandomValues(iv);
+  return crypto.subtle.encrypt({ name: 'AES-GCM', iv: iv }, key, encoder.encode(text))
+    .then(function (data) {
+      return { iv: toHex(iv), data: toHex(data) };
+    });
+}
+
+// Rejects if the key is wrong, which is how a wrong code shows up.
+function decrypt(key, message) {
+  return crypto.subtle.decrypt({ name: 'AES-GCM', iv: fromHex(message.iv) }, key,
+                               fromHex(message.data))
+    .then(function (text) {
+      return new TextDecoder().decode(text);
+    });
+}
+
+function pairRequest(method, path, body) {
+  return fetch(pairBasePath + path, { method: method, body: body }).then(function (response) {
+    return response.text().then(function (text) {
+      if (!response.ok) {
+        throw new Error(response.status === 404 ? 'The code has expired or is unknown' : text);
+      }
+      return { status: response.status, text: text };
+    });
+  });
+}
+
+// A mailbox the opener or joiner holds a place in.
+function Mailbox(nameplate, token) {
+  this.query = '?code=' + nameplate + '&token=' + encodeURIComponent(token);
+  this.closed = false;
+}
+
+Mailbox.prototype.send = function (message) {
+  return pairRequest('POST', '/1/pair/send' + this.query, JSON.stringify(message));
+};
+
+// The next message from the other side, long-polling until there is one.
+Mailbox.prototype.receive = function () {
+  var mailbox = this;
+  return pairRequest('GET', '/1/pair/receive' + this.query).then(function (response) {
+    if (mailbox.closed) {
+      throw new Error('Cancelled');
+    }
+    return response.status === 204 ? mailbox.receive() : JSON.parse(response.text);
+  });
+};
+
+Mailbox.prototype.close = function () {
+  if (!this.closed) {
+    this.closed = true;
+    pairRequest('POST', '/1/pair/close' + this.query).catch(function () {});
+  }
+};
+
+function randomWord(words) {
+  var index = new Uint32Array(1);
+  crypto.getRandomValues(index);
+  return words[index[0] % words.length];
+}
+
+// Opens a mailbox and makes up the code for it, passing it to onCode, then hands the paste id to
+// the device that joins with it. Resolves once the other side has it, and rejects if it typed the
+// wrong code, or the code expired first. cancel closes the mailbox, for when the paste goes away.
+function offerPairing(id, onCode, onJoined) {
+  var mailbox = null;
+  var cancelled = false;
+  var code;
+  var done = Promise.all([
+    pairRequest('POST', '/1/pair/open'),
+    fetch(pairBasePath + '/words.txt').then(function (response) { return response.text(); })
+  ]).then(function (results) {
+    var parts = results[0].text.split(',');
+    var words = results[1].split(/\s+/).filter(Boolean);
+    mailbox = new Mailbox(parts[0], parts[1]);
+    if (cancelled) {
+      mailbox.close();
+      throw new Error('Cancelled');
+    }
+    code = parts[0] + '-' + randomWord(words) + '-' + randomWord(words);
+    onCode(code);
+    return spake2Start(true, code);
+  }).then(function (start) {
+    return mailbox.send({ type: 'pake', message: start.message }).then(function () {
+      return mailbox.receive();
+    }).then(function (reply) {
+      onJoined();
+      return spake2Finish(start.state, reply.message);
+    });
+  }).then(function (key) {
+    return encrypt(key, id);
+  }).then(function (encrypted) {
+    encrypted.type = 'id';
+    return mailbox.send(encrypted);
+  }).then(function () {
+    return mailbox.receive();
+  }).then(function (reply) {
+    mailbox.close();
+    if (reply.type !== 'received') {
+      throw new Error('The other device typed the wrong code');
+    }
+  }, function (e) {
+    if (mailbox) {
+      mailbox.close();
+    }
+    throw e;
+  });
+  return {
+    done: done,
+    cancel: function () {
+      cancelled = true;
+      if (mailbox) {
+        mailbox.close();
+      }
+    }
+  };
+}
+
+// "7-guitar-ocean" however it was typed: any spacing or punctuation between the parts, and any
+// case.
+function normalizeCode(typed) {
+  var parts = typed.toLowerCase().split(/[^a-z0-9]+/).filter(Boolean);
+  if (parts.length < 2 || !/^[0-9]+$/.test(parts[0])) {
+    return null;
+  }
+  return parts.join('-');
+}
+
+// Joins the mailbox the code names and resolves to the paste id the opener sends.
+function receivePairing(code) {
+  var nameplate = code.split('-')[0];
+  var mailbox;
+  var key;
+  return pairRequest('POST', '/1/pair/join?code=' + nameplate).then(function (response) {
+    mailbox = new Mailbox(nameplate, response.text);
+    return spake2Start(false, code);
+  }).then(function (start) {
+    return mailbox.send({ type: 'pake', message: start.message }).then(function () {
+      return mailbox.receive();
+    }).then(function (message) {
+      return spake2Finish(start.state, message.message);
+    });
+  }).then(function (derived) {
+    key = derived;
+    return mailbox.receive();
+  }).then(function (message) {
+    return decrypt(key, message).catch(function () {
+      return mailbox.send({ type: 'wrong-code' }).then(function () {
+        throw new Error('Wrong code');
+      });
+    });
+  }).then(function (id) {
+    return mailbox.send({ type: 'received' }).then(function () {
+      mailbox.close();
+      return id;
+    });
+  }, function (e) {
+    if (mailbox) {
+      mailbox.close();
+    }
+    throw e;
+  });
+}
+
+var receiveForm = document.getElementById('receive-form');
+
+if (receiveForm) {
+  var receiveCode = document.getElementById('receive-code');
+  var receiveButton = document.getElementById('receive-button');
+  var receiveStatus = document.getElementById('receive-status');
+
+  receiveForm.onsubmit = function (e) {
+    e.preventDefault();
+    var code = normalizeCode(receiveCode.value);
+    if (!code) {
+      receiveStatus.innerText = 'Codes look like 7-guitar-ocean: a number, then words';
+      return;
+    }
+
+    receiveButton.disabled = true;
+    receiveStatus.innerText = 'Exchanging keys...';
+    receivePairing(code).then(function (id) {
+      receiveStatus.innerText = 'Got it, opening the paste';
+      window.location.href = pairBasePath + '/p/' + encodeURIComponent(id);
+    }, function (e) {
+      // the mailbox is spent either way, so a retry needs a fresh code
+      receiveStatus.innerText = e.message + '. Ask for a new code and try again.';
+      receiveButton.disabled = false;
+    });
+  };
+}
@@ -0,0 +1,375 @@
+// Mailboxes for pairing two devices with a short code like "7-guitar-ocean", as magic-wormhole
+// does. Only the number, the nameplate, is ever sent to the server. The words are the password
+// for a PAKE (e.g. SPAKE2) the two sides run by passing messages through their mailbox, leaving
+// them with a key the server never learns, and then with it whatever they need to find each
+// other's paste, like its id encrypted. The payload itself goes through the usual relay.
+//
+// This is the server's part. The web client's is in pair.js: the uploader page makes up the
+// words from words.txt and runs SPAKE2 with whoever types the code into /receive, which is then
+// sent to the paste.
+//
+// A mailbox takes one opener and one joiner, so whoever guesses a nameplate first gets a single
+// try at the words, which the PAKE makes fail for both sides if wrong.
+
+use bytes::Bytes;
+use futures::sync::oneshot;
+use rand::prelude::*;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Plenty for PAKE messages and an encrypted id.
+pub const MAX_MESSAGE_LENGTH: u64 = 4096;
+const MAX_QUEUED_MESSAGES: usize = 16;
+const MAX_NAMEPLATE: u32 = 999_999;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+enum Side {
+    Opener,
+    Joiner,
+}
+
+impl Side {
+    fn index(self) -> usize {
+        match self {
+            Side::Opener => 0,
+            Side::Joiner => 1,
+        }
+    }
+
+    fn other(self) -> Side {
+        match self {
+            Side::Opener => Side::Joiner,
+            Side::Joiner => Side::Opener,
+        }
+    }
+}
+
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub enum PairError {
+    Unknown,
+    // someone already joined
+    Taken,
+    // the token is for neither side
+    Forbidden,
+    // the other side isn't picking up its messages
+    Full,
+}
+
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub enum Received {
+    Message(Bytes),
+    // Answered with the next message, or dropped if the mailbox is closed before one comes.
+    Wait(oneshot::Receiver<Bytes>),
+}
+
+#[cfg_attr(debug_assertions, derive(Debug))]
+struct Mailbox {
+    tokens: [Option<String>; 2],
+    // Messages for each side, and its long-polls waiting for one.
+    inbox: [VecDeque<Bytes>; 2],
+    waiting: [VecDeque<oneshot::Sender<Bytes>>; 2],
+    expires: Instant,
+}
+
+impl Mailbox {
+    fn side(&self, token: &str) -> Result<Side, PairError> {
+        if self.tokens[0].as_deref() == Some(token) {
+            Ok(Side::Opener)
+        } else if self.tokens[1].as_deref() == Some(token) {
+            Ok(Side::Joiner)
+        } else {
+            Err(PairError::Forbidden)
+        }
+    }
+}
+
+#[derive(Default)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub struct Pairings {
+    mailboxes: Mutex<HashMap<u32, Mailbox>>,
+}
+
+impl Pairings {
+    // A fresh nameplate for the opener holding token. Kept as short as the number of mailboxes in
+    // use allows, and None if they are all taken.
+    pub fn open(&self, token: String, expires: Instant) -> Option<u32> {
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+
+        let mut max = 9;
+        while max < MAX_NAMEPLATE && mailboxes.len() as u32 >= max / 2 {
+            max = max * 10 + 9;
+        }
+        if mailboxes.len() as u32 >= max {
+            return None;
+        }
+
+        let mut rng = thread_rng();
+        let nameplate = loop {
+            let candidate = rng.gen_range(1, max + 1);
+            if !mailboxes.contains_key(&candidate) {
+                break candidate;
+            }
+        };
+        mailboxes.insert(
+            nameplate,
+            Mailbox {
+                tokens: [Some(token), None],
+                inbox: Default::default(),
+                waiting: Default::default(),
+                expires,
+            },
+        );
+        Some(nameplate)
+    }
+
+    pub fn join(&self, nameplate: u32, token: String) -> Result<(), PairError> {
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        let mailbox = mailboxes.get_mut(&nameplate).ok_or(PairError::Unknown)?;
+        if mailbox.tokens[1].is_some() {
+            return Err(PairError::Taken);
+        }
+        mailbox.tokens[1] = Some(token);
+        Ok(())
+    }
+
+    // Passes the message to the other side, straight to a waiting long-poll if there is one.
+    pub fn send(&self, nameplate: u32, token: &str, message: Bytes) -> Result<(), PairError> {
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        let mailbox = mailboxes.get_mut(&nameplate).ok_or(PairError::Unknown)?;
+        let to = mailbox.side(token)?.other().index();
+
+        let mut message = message;
+        while let Some(waiter) = mailbox.waiting[to].pop_front() {
+            match waiter.send(message) {
+                Ok(()) => return Ok(()),
+                // that long-poll is gone, try the next
+                Err(m) => message = m,
+            }
+        }
+
+        if mailbox.inbox[to].len() >= MAX_QUEUED_MESSAGES {
+            return Err(PairError::Full);
+        }
+        mailbox.inbox[to].push_back(message);
+        Ok(())
+    }
+
+    pub fn receive(&self, nameplate: u32, token: &str) -> Result<Received, PairError> {
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        let mailbox = mailboxes.get_mut(&nameplate).ok_or(PairError::Unknown)?;
+        let side = mailbox.side(token)?.index();
+
+        if let Some(message) = mailbox.inbox[side].pop_front() {
+            return Ok(Received::Message(message));
+        }
+        let (waiter, message) = oneshot::channel();
+        // drop the long-polls that have already given up
+        mailbox.waiting[side].retain(|w| !w.is_canceled());
+        mailbox.waiting[side].push_back(waiter);
+        Ok(Received::Wait(message))
+    }
+
+    // Either side can close the mailbox, freeing the nameplate.
+    pub fn close(&self, nameplate: u32, token: &str) -> Result<(), PairError> {
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        mailboxes
+            .get(&nameplate)
+            .ok_or(PairError::Unknown)?
+            .side(token)?;
+        mailboxes.remove(&nameplate);
+        Ok(())
+    }
+
+    // The nameplate may have been reused since the deadline was set, so it's checked again.
+    pub fn expire(&self, nameplate: u32, now: Instant) {
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        if mailboxes.get(&nameplate).is_some_and(|m| m.expires <= now) {
+            mailboxes.remove(&nameplate);
+        }
+    }
+
+    pub fn clear(&self) {
+        self.mailboxes.lock().unwrap().clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.mailboxes.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+    use std::time::Duration;
+
+    fn opened(pairings: &Pairings) -> u32 {
+        let in_a_minute = Instant::now() + Duration::from_secs(60);
+        pairings.open("opener".to_owned(), in_a_minute).unwrap()
+    }
+
+    fn message(received: Result<Received, PairError>) -> Option<Bytes> {
+        match received {
+            Ok(Received::Message(message)) => Some(message),
+            Ok(Received::Wait(_)) => None,
+            Err(_) => panic!("no message to be had"),
+        }
+    }
+
+    fn long_poll(received: Result<Received, PairError>) -> oneshot::Receiver<Bytes> {
+        match received {
+            Ok(Received::Wait(waiting)) => waiting,
+            _ => panic!("nothing was sent"),
+        }
+    }
+
+    #[test]
+    fn nameplates_stay_short_while_few_are_open() {
+        let pairings = Pairings::default();
+        let nameplate = opened(&pairings);
+        assert!((1..=9).contains(&nameplate));
+        assert_eq!(pairings.len(), 1);
+    }
+
+    #[test]
+    fn a_mailbox_takes_one_joiner() {
+        let pairings = Pairings::default();
+        let nameplate = opened(&pairings);
+        assert!(pairings.join(nameplate, "joiner".to_owned()).is_ok());
+        assert!(matches!(
+            pairings.join(nameplate, "guesser".to_owned()),
+            Err(PairError::Taken)
+        ));
+        assert!(matches!(
+            pairings.join(nameplate + 1, "joiner".to_owned()),
+            Err(PairError::Unknown)
+        ));
+    }
+
+    #[test]
+    fn messages_go_to_the_other_side() {
+        let pairings = Pairings::default();
+        let nameplate = opened(&pairings);
+        assert!(pairings.join(nameplate, "joiner".to_owned()).is_ok());
+
+        assert!(pairings
+            .send(nameplate, "opener", Bytes::from("to joiner"))
+            .is_ok());
+        assert!(pairings
+            .send(nameplate, "joiner", Bytes::from("to opener"))
+            .is_ok());
+        assert_eq!(
+            message(pairings.receive(nameplate, "joiner")),
+            Some(Bytes::from("to joiner"))
+        );
+        assert_eq!(
+            message(pairings.receive(nameplate, "opener")),
+            Some(Bytes::from("to opener"))
+        );
+        assert_eq!(message(pairings.receive(nameplate, "opener")), None);
+    }
+
+    #[test]
+    fn a_waiting_receive_gets_the_next_message() {
+        let pairings = Pairings::default();
+        let nameplate = opened(&pairings);
+        assert!(pairings.join(nameplate, "joiner".to_owned()).is_ok());
+
+        let waiting = long_poll(pairings.receive(nameplate, "joiner"));
+        assert!(pairings
+            .send(nameplate, "opener", Bytes::from("hello"))
+            .is_ok());
+        assert_eq!(waiting.wait().unwrap(), Bytes::from("hello"));
+        // it went to the long-poll, not the inbox
+        assert_eq!(message(pairings.receive(nameplate, "joiner")), None);
+    }
+
+    #[test]
+    fn a_given_up_receive_does_not_swallow_messages() {
+        let pairings = Pairings::default();
+        let nameplate = opened(&pairings);
+        assert!(pairings.join(nameplate, "joiner".to_owned()).is_ok());
+
+        drop(pairings.receive(nameplate, "joiner"));
+        assert!(pairings
+            .send(nameplate, "opener", Bytes::from("hello"))
+            .is_ok());
+        assert_eq!(
+            message(pairings.receive(nameplate, "joiner")),
+            Some(Bytes::from("hello"))
+        );
+    }
+
+    #[test]
+    fn other_tokens_are_refused() {
+        let pairings = Pairings::default();
+        let nameplate = opened(&pairings);
+        assert!(pairings.join(nameplate, "joiner".to_owned()).is_ok());
+
+        assert!(matches!(
+            pairings.send(nameplate, "guesser", Bytes::from("hi")),
+            Err(PairError::Forbidden)
+        ));
+        assert!(matches!(
+            pairings.receive(nameplate, "guesser"),
+            Err(PairError::Forbidden)
+        ));
+        assert!(matches!(
+            pairings.close(nameplate, "guesser"),
+            Err(PairError::Forbidden)
+        ));
+        assert_eq!(pairings.len(), 1);
+    }
+
+    #[test]
+    fn unread_messages_are_limited() {
+        let pairings = Pairings::default();
+        let nameplate = opened(&pairings);
+        for _ in 0..MAX_QUEUED_MESSAGES {
+            assert!(pairings
+                .send(nameplate, "opener", Bytes::from("hi"))
+                .is_ok());
+        }
+        assert!(matches!(
+            pairings.send(nameplate, "opener", Bytes::from("hi")),
+            Err(PairError::Full)
+        ));
+    }
+
+    #[test]
+    fn closing_frees_the_nameplate() {
+        let pairings = Pairings::default();
+        let nameplate = opened(&pairings);
+        assert!(pairings.join(nameplate, "joiner".to_owned()).is_ok());
+
+        let waiting = long_poll(pairings.receive(nameplate, "opener"));
+        assert!(pairings.close(nameplate, "joiner").is_ok());
+        assert_eq!(pairings.len(), 0);
+        // the long-poll is dropped, not left hanging
+        assert!(waiting.wait().is_err());
+        assert!(matches!(
+            pairings.receive(nameplate, "opener"),
+            Err(PairError::Unknown)
+        ));
+    }
+
+    #[test]
+    fn mailboxes_expire_at_their_deadline() {
+        let pairings = Pairings::default();
+        let now = Instant::now();
+        let nameplate = pairings
+            .open("opener".to_owned(), now + Duration::from_secs(60))
+            .unwrap();
+
+        pairings.expire(nameplate, now);
+        assert_eq!(pairings.len(), 1);
+        pairings.expire(nameplate, now + Duration::from_secs(60));
+        assert_eq!(pairings.len(), 0);
+        assert!(matches!(
+            pairings.join(nameplate, "joiner".to_owned()),
+            Err(PairError::Unknown)
+        ));
+    }
+}
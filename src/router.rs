@@ -0,0 +1,148 @@
+// Matches requests against path patterns like "/2/pastes/{id}", where a segment in braces stands
+// for any single non-empty segment of the path and is captured under that name.
+
+use hyper::Method;
+use url::percent_encoding::percent_decode;
+
+#[cfg_attr(debug_assertions, derive(Debug))]
+enum Segment {
+    Literal(&'static str),
+    Capture(&'static str),
+}
+
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub struct Params(Vec<(&'static str, String)>);
+
+impl Params {
+    // Percent-decoded.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub enum Miss {
+    NotFound,
+    // The path is known, here are the methods it takes.
+    MethodNotAllowed(Vec<Method>),
+}
+
+pub struct Router<H> {
+    routes: Vec<(Method, Vec<Segment>, H)>,
+}
+
+impl<H> Default for Router<H> {
+    fn default() -> Router<H> {
+        Router { routes: Vec::new() }
+    }
+}
+
+impl<H> Router<H> {
+    pub fn add(mut self, method: Method, pattern: &'static str, handler: H) -> Router<H> {
+        let segments = pattern
+            .split('/')
+            .skip(1)
+            .map(|s| {
+                if s.starts_with('{') && s.ends_with('}') {
+                    Segment::Capture(&s[1..s.len() - 1])
+                } else {
+                    Segment::Literal(s)
+                }
+            })
+            .collect();
+        self.routes.push((method, segments, handler));
+        self
+    }
+
+    pub fn route(&self, method: &Method, path: &str) -> Result<(&H, Params), Miss> {
+        let parts: Vec<&str> = path.split('/').skip(1).collect();
+        let mut allowed = Vec::new();
+
+        for (m, segments, handler) in &self.routes {
+            let params = match match_segments(segments, &parts) {
+                Some(params) => params,
+                None => continue,
+            };
+            if m == method {
+                return Ok((handler, params));
+            }
+            allowed.push(m.clone());
+        }
+
+        if allowed.is_empty() {
+            Err(Miss::NotFound)
+        } else {
+            Err(Miss::MethodNotAllowed(allowed))
+        }
+    }
+}
+
+fn match_segments(segments: &[Segment], parts: &[&str]) -> Option<Params> {
+    if segments.len() != parts.len() {
+        return None;
+    }
+
+    let mut params = Vec::new();
+    for (segment, part) in segments.iter().zip(parts) {
+        match segment {
+            Segment::Literal(literal) if literal == part => {}
+            Segment::Capture(name) if !part.is_empty() => {
+                let value = percent_decode(part.as_bytes()).decode_utf8_lossy();
+                params.push((*name, value.into_owned()));
+            }
+            _ => return None,
+        }
+    }
+    Some(Params(params))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router() -> Router<&'static str> {
+        Router::default()
+            .add(Method::POST, "/2/pastes", "create")
+            .add(Method::GET, "/2/pastes/{id}", "download")
+            .add(Method::PUT, "/2/pastes/{id}", "upload")
+    }
+
+    #[test]
+    fn captures_are_decoded() {
+        let router = router();
+        let (handler, params) = router.route(&Method::PUT, "/2/pastes/a%20b").ok().unwrap();
+        assert_eq!(*handler, "upload");
+        assert_eq!(params.get("id"), Some("a b"));
+        assert_eq!(params.get("other"), None);
+    }
+
+    #[test]
+    fn unknown_paths_are_not_found() {
+        let router = router();
+        for path in &["/2/paste", "/2/pastes/", "/2/pastes/a/b", "/2", ""] {
+            assert!(
+                matches!(router.route(&Method::GET, path), Err(Miss::NotFound)),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn known_paths_list_the_methods_they_take() {
+        let router = router();
+        match router.route(&Method::DELETE, "/2/pastes/a") {
+            Err(Miss::MethodNotAllowed(allowed)) => {
+                assert_eq!(allowed, vec![Method::GET, Method::PUT])
+            }
+            _ => panic!("should be a 405"),
+        }
+        assert!(matches!(
+            router.route(&Method::GET, "/2/pastes"),
+            Err(Miss::MethodNotAllowed(_))
+        ));
+    }
+}
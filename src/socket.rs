@@ -0,0 +1,319 @@
+// An uploader can hold a WebSocket open instead of re-POSTing the paste after every download.
+// The server only asks for content once a downloader turns up:
+//
+//   server -> uploader, text:   {"type":"request","transfer":N,"offset":O,"length":L}
+//   uploader -> server, binary: 4-byte big-endian N, followed by content
+//   server -> uploader, text:   {"type":"done","transfer":N} or {"type":"aborted","transfer":N}
+//
+// The content for a transfer is L bytes starting at offset O of the paste, and may be split over
+// any number of binary messages. Several transfers can be in progress at once.
+
+use base64;
+use futures::sync::mpsc;
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+use hyper::body::Sender;
+use hyper::header::{self, HeaderMap};
+use hyper::upgrade::Upgraded;
+use hyper::Chunk;
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, VecDeque};
+use tokio_tungstenite::tungstenite::protocol::{Message, Role};
+use tokio_tungstenite::WebSocketStream;
+
+// Requests for content the uploader hasn't started on yet, past which downloaders wait their turn.
+pub const MAX_PENDING_REQUESTS: usize = 16;
+
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub struct SocketRequest {
+    pub offset: u64,
+    pub length: u64,
+    pub chunks: Sender,
+}
+
+// The Sec-WebSocket-Accept value to answer an upgrade request with, if it is one.
+pub fn accept_key(headers: &HeaderMap) -> Option<String> {
+    let upgrade = headers.get(header::UPGRADE)?.to_str().ok()?;
+    if !upgrade.eq_ignore_ascii_case("websocket") {
+        return None;
+    }
+    let key = headers.get(header::SEC_WEBSOCKET_KEY)?;
+
+    let mut sha1 = Sha1::new();
+    sha1.input(key.as_bytes());
+    sha1.input(b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
+    Some(base64::encode(&sha1.result()))
+}
+
+struct Transfer {
+    // Only takes a chunk at a time, so the uploader is held back to the downloader's pace.
+    chunks: Sender,
+    remaining: u64,
+}
+
+pub struct Session {
+    ws: WebSocketStream<Upgraded>,
+    requests: mpsc::Receiver<SocketRequest>,
+    transfers: HashMap<u32, Transfer>,
+    // Content received for a transfer whose downloader has no room for it yet. Nothing more is
+    // read from the socket until it has.
+    pending: Option<(u32, Chunk)>,
+    next_transfer: u32,
+    outgoing: VecDeque<Message>,
+    closing: bool,
+}
+
+impl Session {
+    pub fn new(upgraded: Upgraded, requests: mpsc::Receiver<SocketRequest>) -> Session {
+        Session {
+            ws: WebSocketStream::from_raw_socket(upgraded, Role::Server, None),
+            requests,
+            transfers: HashMap::new(),
+            pending: None,
+            next_transfer: 0,
+            outgoing: VecDeque::new(),
+            closing: false,
+        }
+    }
+
+    fn start_transfer(&mut self, request: SocketRequest) {
+        let transfer = self.next_transfer;
+        self.next_transfer = self.next_transfer.wrapping_add(1);
+
+        self.outgoing.push_back(Message::Text(format!(
+            r#"{{"type":"request","transfer":{},"offset":{},"length":{}}}"#,
+            transfer, request.offset, request.length
+        )));
+
+        if request.length == 0 {
+            // nothing to wait for, dropping the sender ends the download
+            self.finish_transfer(transfer, "done");
+        } else {
+            self.transfers.insert(
+                transfer,
+                Transfer {
+                    chunks: request.chunks,
+                    remaining: request.length,
+                },
+            );
+        }
+    }
+
+    fn finish_transfer(&mut self, transfer: u32, outcome: &str) {
+        self.transfers.remove(&transfer);
+        self.outgoing.push_back(Message::Text(format!(
+            r#"{{"type":"{}","transfer":{}}}"#,
+            outcome, transfer
+        )));
+    }
+
+    fn receive(&mut self, mut data: Vec<u8>) {
+        if data.len() < 4 {
+            return;
+        }
+        let transfer = u32::from(data[0]) << 24
+            | u32::from(data[1]) << 16
+            | u32::from(data[2]) << 8
+            | u32::from(data[3]);
+        data.drain(..4);
+
+        // if there's no such transfer, it was probably aborted already
+        if let Some(t) = self.transfers.get(&transfer) {
+            // anything past the requested length is dropped
+            if data.len() as u64 > t.remaining {
+                data.truncate(t.remaining as usize);
+            }
+            self.pending = Some((transfer, Chunk::from(data)));
+        }
+    }
+
+    // Pass on what was received last, if its downloader has room. False if it hasn't yet.
+    fn deliver(&mut self) -> bool {
+        let (transfer, chunk) = match self.pending.take() {
+            Some(pending) => pending,
+            None => return true,
+        };
+
+        let outcome = match self.transfers.get_mut(&transfer) {
+            Some(t) => match t.chunks.poll_ready() {
+                Ok(Async::NotReady) => {
+                    self.pending = Some((transfer, chunk));
+                    return false;
+                }
+                Ok(Async::Ready(())) => {
+                    t.remaining -= chunk.len() as u64;
+                    if t.chunks.send_data(chunk).is_err() {
+                        Some("aborted")
+                    } else if t.remaining == 0 {
+                        Some("done")
+                    } else {
+                        None
+                    }
+                }
+                // the downloader went away
+                Err(_) => Some("aborted"),
+            },
+            None => None,
+        };
+
+        if let Some(outcome) = outcome {
+            self.finish_transfer(transfer, outcome);
+        }
+        true
+    }
+}
+
+impl Future for Session {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            let mut progress = false;
+
+            while !self.closing {
+                match self.requests.poll() {
+                    Ok(Async::Ready(Some(request))) => {
+                        self.start_transfer(request);
+                        progress = true;
+                    }
+                    // the paste was retired, or the uploader opened another socket
+                    Ok(Async::Ready(None)) | Err(()) => self.closing = true,
+                    Ok(Async::NotReady) => break,
+                }
+            }
+
+            while let Some(message) = self.outgoing.pop_front() {
+                match self.ws.start_send(message) {
+                    Ok(AsyncSink::Ready) => progress = true,
+                    Ok(AsyncSink::NotReady(message)) => {
+                        self.outgoing.push_front(message);
+                        break;
+                    }
+                    Err(_) => return Err(()),
+                }
+            }
+
+            if self.closing && self.outgoing.is_empty() {
+                return self.ws.close().map_err(|_| ());
+            }
+            if self.ws.poll_complete().is_err() {
+                return Err(());
+            }
+
+            if !self.deliver() {
+                // woken again once the downloader has read some
+                return Ok(Async::NotReady);
+            }
+
+            match self.ws.poll() {
+                Ok(Async::Ready(Some(Message::Binary(data)))) => {
+                    self.receive(data);
+                    progress = true;
+                }
+                Ok(Async::Ready(Some(_))) => progress = true,
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
+                Ok(Async::NotReady) => {}
+                Err(_) => return Err(()),
+            }
+
+            if !progress {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+
+// Downloaders still waiting on the socket get an error rather than a response that just stops
+// short.
+impl Drop for Session {
+    fn drop(&mut self) {
+        for (_, transfer) in self.transfers.drain() {
+            transfer.chunks.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{Future, Sink, Stream};
+    use hyper::header;
+    use hyper::{Body, Client, Request, StatusCode};
+    use std::collections::HashMap;
+    use tokio::runtime::Runtime;
+    use tokio_tungstenite::tungstenite::protocol::{Message, Role};
+    use tokio_tungstenite::WebSocketStream;
+
+    use cluster::Cluster;
+    use testing;
+
+    // A paste of "hello" with an uploader on a socket that sends `sent` of it and then hangs up,
+    // and what the downloader got.
+    fn download_from_socket(sent: &'static [u8]) -> Result<(StatusCode, String), hyper::Error> {
+        let mut runtime = Runtime::new().unwrap();
+        let (listener, addr) = testing::listen();
+        testing::serve(
+            &mut runtime,
+            listener,
+            Cluster::new(None, HashMap::new(), false),
+        );
+        let client = Client::new();
+
+        let create = Request::post(format!("http://{}/2/pastes?length=5", addr))
+            .body(Body::empty())
+            .unwrap();
+        let (status, created) = runtime.block_on(testing::fetch(&client, create)).unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        let id = testing::json_field(&created, "id");
+        let secret = testing::json_field(&created, "secret");
+
+        let upgrade = Request::get(format!(
+            "http://{}/1/file/socket?id={}&secret={}",
+            addr, id, secret
+        ))
+        .header(header::CONNECTION, "upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(header::SEC_WEBSOCKET_VERSION, "13")
+        .header(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+        .body(Body::empty())
+        .unwrap();
+        let ws = runtime
+            .block_on(client.request(upgrade).and_then(|response| {
+                assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+                response.into_body().on_upgrade()
+            }))
+            .map(|upgraded| WebSocketStream::from_raw_socket(upgraded, Role::Client, None))
+            .unwrap();
+
+        // answer the first request, then drop the socket
+        runtime.spawn(
+            ws.into_future()
+                .map_err(|(e, _)| e)
+                .and_then(move |(request, ws)| {
+                    let request = request.unwrap().into_text().unwrap();
+                    assert!(request.contains(r#""type":"request","transfer":0"#));
+                    let mut message = vec![0, 0, 0, 0];
+                    message.extend_from_slice(sent);
+                    ws.send(Message::Binary(message))
+                })
+                .map(drop)
+                .map_err(|e| panic!("socket error: {}", e)),
+        );
+
+        let download = Request::get(format!("http://{}/1/file/download?id={}", addr, id))
+            .body(Body::empty())
+            .unwrap();
+        runtime.block_on(testing::fetch(&client, download))
+    }
+
+    #[test]
+    fn downloads_are_fed_from_the_socket() {
+        let (status, content) = download_from_socket(b"hello").unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content, "hello");
+    }
+
+    #[test]
+    fn downloads_cut_short_by_the_socket_closing_fail() {
+        assert!(download_from_socket(b"he").is_err());
+    }
+}
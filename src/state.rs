@@ -0,0 +1,122 @@
+use rand::prelude::*;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use toml;
+
+// What's kept of a paste across restarts. Uploaders have to re-attach, so only enough to
+// recognize them (and the downloaders' links) is needed.
+#[derive(Serialize, Deserialize)]
+pub struct SavedPaste {
+    pub id: String,
+    // HMAC-SHA256, keyed with the key file, of the SHA-256 of the secret.
+    #[serde(default)]
+    pub secret_hmac: Option<String>,
+    // The plain SHA-256 of the secret, as saved by older versions.
+    #[serde(default)]
+    pub secret_sha256: Option<String>,
+    pub length: u64,
+    #[serde(default)]
+    pub cache: bool,
+    #[serde(default)]
+    pub encoding: Option<String>,
+    // seconds since the Unix epoch
+    pub expires: u64,
+    // The timeout_secs it was made with. Older versions didn't save it, so the configured one is
+    // used instead.
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    // last, as TOML can't have plain values after an array of tables
+    #[serde(default)]
+    pub files: Vec<SavedFile>,
+}
+
+// An entry of a bundle's manifest.
+#[derive(Serialize, Deserialize)]
+pub struct SavedFile {
+    pub name: String,
+    pub length: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedState {
+    #[serde(default)]
+    paste: Vec<SavedPaste>,
+}
+
+// A missing file just means there is nothing to restore.
+pub fn load(path: &str) -> io::Result<Vec<SavedPaste>> {
+    let mut state_string = String::new();
+    match File::open(path) {
+        Ok(mut file) => file.read_to_string(&mut state_string)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    match toml::from_str::<SavedState>(&state_string) {
+        Ok(state) => Ok(state.paste),
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+}
+
+// Written to a temporary file first so a crash mid-write leaves the previous state intact.
+pub fn save(path: &str, paste: Vec<SavedPaste>) -> io::Result<()> {
+    let state_string = match toml::to_string(&SavedState { paste }) {
+        Ok(s) => s,
+        Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+    };
+
+    let temp_path = format!("{}.tmp", path);
+    {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(state_string.as_bytes())?;
+        file.sync_all()?;
+    }
+    fs::rename(&temp_path, path)
+}
+
+// The key for hashing secrets with, kept apart from the state file so that its hashes can't be
+// checked against guesses without it. Made up and written to path if there isn't one yet, in
+// which case the bool is true.
+pub fn load_key(path: &str) -> io::Result<([u8; 32], bool)> {
+    let mut key = [0; 32];
+    match fs::read_to_string(path) {
+        Ok(ref hex) if from_hex(hex.trim(), &mut key) => Ok((key, false)),
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} doesn't hold a key", path),
+        )),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            thread_rng().fill(&mut key);
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+            let mut file = options.open(path)?;
+            file.write_all(to_hex(&key).as_bytes())?;
+            file.sync_all()?;
+            Ok((key, true))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// False if hex isn't exactly bytes.len() bytes of hex.
+pub fn from_hex(hex: &str, bytes: &mut [u8]) -> bool {
+    if hex.len() != bytes.len() * 2 || !hex.is_ascii() {
+        return false;
+    }
+    for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        match std::str::from_utf8(digits).map(|d| u8::from_str_radix(d, 16)) {
+            Ok(Ok(b)) => *byte = b,
+            _ => return false,
+        }
+    }
+    true
+}
@@ -0,0 +1,52 @@
+// Nodes served on ephemeral localhost ports, for tests that talk to the server over HTTP.
+
+use futures::{future, Future, Stream};
+use hyper::client::connect::Connect;
+use hyper::service::service_fn;
+use hyper::{Body, Client, Request, Server, StatusCode};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+
+use cluster::Cluster;
+use {service, InFlight};
+
+// Bound before it is served, so other nodes can be told where it is.
+pub fn listen() -> (TcpListener, SocketAddr) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    (listener, addr)
+}
+
+pub fn serve(runtime: &mut Runtime, listener: TcpListener, cluster: Cluster) {
+    let in_flight = Arc::new(InFlight::new());
+    let cluster = Arc::new(cluster);
+    runtime.spawn(future::lazy(move || {
+        Server::from_tcp(listener)
+            .unwrap()
+            .serve(move || service_fn(service(in_flight.clone(), cluster.clone())))
+            .map_err(|e| panic!("server error: {}", e))
+    }));
+}
+
+// The status and the whole body.
+pub fn fetch<C: Connect + 'static>(
+    client: &Client<C>,
+    req: Request<Body>,
+) -> impl Future<Item = (StatusCode, String), Error = hyper::Error> {
+    client.request(req).and_then(|response| {
+        let status = response.status();
+        response
+            .into_body()
+            .concat2()
+            .map(move |body| (status, String::from_utf8_lossy(&body).into_owned()))
+    })
+}
+
+// A string field of a JSON object, found the simple way.
+pub fn json_field(json: &str, name: &str) -> String {
+    let key = format!("\"{}\":\"", name);
+    let start = json.find(&key).expect("field missing") + key.len();
+    let end = start + json[start..].find('"').unwrap();
+    json[start..end].to_owned()
+}
@@ -0,0 +1,72 @@
+use futures::future::Either;
+use futures::{Future, Stream};
+use futures_timer::Delay;
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{NoClientAuth, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+// A client that hasn't finished its handshake by then is dropped, so it can't hold up the others.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const CONCURRENT_HANDSHAKES: usize = 256;
+
+// Loads a PEM certificate chain and private key (PKCS#8 or RSA). With http2, clients are offered
+// h2 through ALPN, otherwise only http/1.1.
+pub fn acceptor(cert_path: &str, key_path: &str, http2: bool) -> io::Result<TlsAcceptor> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_owned());
+
+    let certs = pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+        .map_err(|()| invalid("Could not read certificates"))?;
+
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(key_path)?))
+        .map_err(|()| invalid("Could not read private key"))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(File::open(key_path)?))
+            .map_err(|()| invalid("Could not read private key"))?;
+    }
+    let key = keys
+        .into_iter()
+        .next()
+        .ok_or_else(|| invalid("No private key found"))?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(certs, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if http2 {
+        config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    } else {
+        config.set_protocols(&[b"http/1.1".to_vec()]);
+    }
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// Connections from incoming, once their TLS handshake is done. Failed handshakes are only logged.
+pub fn incoming(
+    incoming: AddrIncoming,
+    acceptor: TlsAcceptor,
+) -> impl Stream<Item = TlsStream<AddrStream>, Error = io::Error> {
+    incoming
+        .map(move |stream| {
+            acceptor
+                .accept(stream)
+                .select2(Delay::new(HANDSHAKE_TIMEOUT))
+                .then(|result| match result {
+                    Ok(Either::A((stream, _))) => Ok(Some(stream)),
+                    Err(Either::A((e, _))) => {
+                        eprintln!("TLS handshake failed: {}", e);
+                        Ok(None)
+                    }
+                    // timed out
+                    _ => Ok(None),
+                })
+        })
+        .buffer_unordered(CONCURRENT_HANDSHAKES)
+        .filter_map(|stream| stream)
+}
@@ -0,0 +1,159 @@
+// Ids made of words, like "correct-horse-battery", for reading out over a call. The list is the
+// English BIP-39 one: 2048 short common words (11 bits each), no two starting with the same four
+// letters, which is what lets a misheard or misspelled word still be recognized.
+
+use rand::prelude::*;
+
+static WORD_LIST: &str = include_str!("words.txt");
+
+lazy_static! {
+    static ref WORDS: Vec<&'static str> = WORD_LIST.split_whitespace().collect();
+}
+
+pub const SEPARATOR: char = '-';
+
+pub fn generate(count: usize) -> String {
+    let mut rng = thread_rng();
+    let words: Vec<&str> = (0..count)
+        .map(|_| *rng.choose(&WORDS[..]).unwrap())
+        .collect();
+    words.join(&SEPARATOR.to_string())
+}
+
+// Whether a can be turned into b by changing, adding, removing or swapping two adjacent
+// characters, at most once.
+fn within_one_edit(a: &[u8], b: &[u8]) -> bool {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let (a, b) = (&a[prefix..], &b[prefix..]);
+    match (a.len(), b.len()) {
+        (x, y) if x == y => {
+            a.len() <= 1
+                || a[1..] == b[1..]
+                || (a.len() >= 2 && a[0] == b[1] && a[1] == b[0] && a[2..] == b[2..])
+        }
+        (x, y) if x == y + 1 => a[1..] == *b,
+        (x, y) if x + 1 == y => *a == b[1..],
+        _ => false,
+    }
+}
+
+// The word from the list meant by what was typed: an exact match, the only word starting with the
+// same four letters, or else the only word one typo away.
+fn correct(typed: &str) -> Option<&'static str> {
+    if let Ok(i) = WORDS.binary_search(&typed) {
+        return Some(WORDS[i]);
+    }
+
+    let unique = |mut candidates: Vec<&'static str>| {
+        if candidates.len() == 1 {
+            candidates.pop()
+        } else {
+            None
+        }
+    };
+
+    if typed.len() >= 4 {
+        let prefix = &typed[..4];
+        let found = unique(
+            WORDS
+                .iter()
+                .filter(|w| w.starts_with(prefix))
+                .cloned()
+                .collect(),
+        );
+        if found.is_some() {
+            return found;
+        }
+    }
+
+    unique(
+        WORDS
+            .iter()
+            .filter(|w| within_one_edit(typed.as_bytes(), w.as_bytes()))
+            .cloned()
+            .collect(),
+    )
+}
+
+// The id a downloader meant, given the words they typed in any case and separated by anything
+// but letters. None if it doesn't come out as count words from the list.
+pub fn normalize(typed: &str, count: usize) -> Option<String> {
+    let typed = typed.to_ascii_lowercase();
+    let words = typed
+        .split(|c: char| !c.is_ascii_alphabetic())
+        .filter(|w| !w.is_empty())
+        .map(correct)
+        .collect::<Option<Vec<&str>>>()?;
+
+    if words.len() == count {
+        Some(words.join(&SEPARATOR.to_string()))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn one_edit(a: &str, b: &str) -> bool {
+        within_one_edit(a.as_bytes(), b.as_bytes())
+    }
+
+    #[test]
+    fn one_edit_of_each_kind() {
+        assert!(one_edit("ocean", "ocean"));
+        assert!(one_edit("ocean", "ocesn"));
+        assert!(one_edit("ocean", "ocan"));
+        assert!(one_edit("ocean", "oceans"));
+        assert!(one_edit("ocean", "ocaen"));
+        assert!(one_edit("ocean", "coean"));
+    }
+
+    #[test]
+    fn two_edits_are_too_many() {
+        assert!(!one_edit("ocean", "ocxxn"));
+        assert!(!one_edit("ocean", "oce"));
+        assert!(!one_edit("ocean", "oceanic"));
+        assert!(!one_edit("ocean", "oaecn"));
+        assert!(!one_edit("ocean", "ocnae"));
+    }
+
+    #[test]
+    fn words_are_normalized_however_they_were_typed() {
+        assert_eq!(
+            normalize("Guitar OCEAN", 2),
+            Some("guitar-ocean".to_owned())
+        );
+        assert_eq!(
+            normalize(" zoo_cat.7ocean ", 3),
+            Some("zoo-cat-ocean".to_owned())
+        );
+    }
+
+    #[test]
+    fn misspelled_words_are_corrected_when_unambiguous() {
+        // by the first four letters, then by one typo
+        assert_eq!(normalize("guitr", 1), Some("guitar".to_owned()));
+        assert_eq!(
+            normalize("zo-cta-oecan", 3),
+            Some("zoo-cat-ocean".to_owned())
+        );
+        // "top" or "two"
+        assert_eq!(normalize("tpo", 1), None);
+        assert_eq!(normalize("bxe", 1), None);
+    }
+
+    #[test]
+    fn the_word_count_has_to_match() {
+        assert_eq!(normalize("guitar-ocean", 3), None);
+        assert_eq!(normalize("", 1), None);
+    }
+
+    #[test]
+    fn generated_ids_normalize_to_themselves() {
+        let id = generate(4);
+        assert_eq!(id.split(SEPARATOR).count(), 4);
+        assert_eq!(normalize(&id.to_uppercase(), 4), Some(id));
+    }
+}